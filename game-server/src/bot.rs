@@ -0,0 +1,273 @@
+use rand::{seq::SliceRandom, thread_rng};
+
+use crate::state::{card_points, Move, PileIndex, PlayerView};
+
+mod ismcts;
+
+pub use ismcts::{Difficulty, IsmctsStrategy};
+
+/// Decides which moves a computer controlled seat makes
+///
+/// Strategies only ever see a `PlayerView` which holds the same information a
+/// human player is sent.
+pub trait Strategy: Send {
+    /// Choose a card from the hand to play this round
+    fn play_card(&mut self, view: &PlayerView) -> u8;
+
+    /// Choose the pile to take when the played card is lower than every pile
+    fn select_pile(&mut self, view: &PlayerView) -> PileIndex;
+}
+
+/// Ask a strategy for whichever move the view calls for
+pub fn decide(strategy: &mut dyn Strategy, view: &PlayerView) -> Move {
+    if view.must_select_pile {
+        Move::SelectPile(strategy.select_pile(view))
+    } else {
+        Move::PlayCard(strategy.play_card(view))
+    }
+}
+
+/// The built-in strategies
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StrategyKind {
+    /// Plays random cards and picks random piles
+    Random,
+    /// Plays the card least likely to end up taking a pile
+    LowestRisk,
+    /// Deliberately forces cheap pile picks and takes the cheapest pile
+    MinBullheads,
+    /// Searches possible futures with information set Monte Carlo tree search
+    Ismcts(Difficulty),
+}
+
+impl StrategyKind {
+    pub fn strategy(self) -> Box<dyn Strategy> {
+        match self {
+            StrategyKind::Random => Box::new(RandomStrategy),
+            StrategyKind::LowestRisk => Box::new(LowestRiskStrategy),
+            StrategyKind::MinBullheads => Box::new(MinBullheadsStrategy),
+            StrategyKind::Ismcts(difficulty) => Box::new(IsmctsStrategy::new(difficulty.budget())),
+        }
+    }
+
+    /// A human friendly name for bots using this strategy
+    pub fn display_name(self) -> &'static str {
+        match self {
+            StrategyKind::Random => "Random Bot",
+            StrategyKind::LowestRisk => "Careful Bot",
+            StrategyKind::MinBullheads => "Bullhead Bot",
+            StrategyKind::Ismcts(Difficulty::Easy) => "Easy Monte Carlo Bot",
+            StrategyKind::Ismcts(Difficulty::Medium) => "Monte Carlo Bot",
+            StrategyKind::Ismcts(Difficulty::Hard) => "Hard Monte Carlo Bot",
+        }
+    }
+}
+
+/// What is played for a human player who ran out of time
+///
+/// Piles are always selected by taking the one with the fewest bullheads.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeoutPolicy {
+    /// Play the lowest card in the hand
+    LowestCard,
+    /// Play the highest card in the hand
+    HighestCard,
+    /// Play the card least likely to end up taking a pile
+    LowestRisk,
+}
+
+impl Strategy for TimeoutPolicy {
+    fn play_card(&mut self, view: &PlayerView) -> u8 {
+        match self {
+            TimeoutPolicy::LowestCard => *view.hand.iter().min().unwrap(),
+            TimeoutPolicy::HighestCard => *view.hand.iter().max().unwrap(),
+            TimeoutPolicy::LowestRisk => LowestRiskStrategy.play_card(view),
+        }
+    }
+
+    fn select_pile(&mut self, view: &PlayerView) -> PileIndex {
+        cheapest_pile(view, |cards| cards.len())
+    }
+}
+
+pub struct RandomStrategy;
+
+impl Strategy for RandomStrategy {
+    fn play_card(&mut self, view: &PlayerView) -> u8 {
+        *view.hand.choose(&mut thread_rng()).unwrap()
+    }
+
+    fn select_pile(&mut self, view: &PlayerView) -> PileIndex {
+        let indexes: Vec<usize> = (0..view.piles.len()).collect();
+        let index = *indexes.choose(&mut thread_rng()).unwrap();
+        PileIndex::from(index)
+    }
+}
+
+pub struct LowestRiskStrategy;
+
+impl Strategy for LowestRiskStrategy {
+    fn play_card(&mut self, view: &PlayerView) -> u8 {
+        *view
+            .hand
+            .iter()
+            .min_by_key(|card| (risk(view, **card), **card))
+            .unwrap()
+    }
+
+    fn select_pile(&mut self, view: &PlayerView) -> PileIndex {
+        cheapest_pile(view, |cards| cards.len())
+    }
+}
+
+pub struct MinBullheadsStrategy;
+
+impl Strategy for MinBullheadsStrategy {
+    fn play_card(&mut self, view: &PlayerView) -> u8 {
+        // When some pile is nearly free, undercutting every pile on purpose
+        // gets rid of a low card at almost no cost.
+        let cheapest = view.piles.iter().map(|p| pile_points(p)).min().unwrap();
+        let lowest_top = view.piles.iter().map(|p| *p.last().unwrap()).min().unwrap();
+        match view.hand.first() {
+            Some(card) if cheapest <= 1 && *card < lowest_top => *card,
+            _ => LowestRiskStrategy.play_card(view),
+        }
+    }
+
+    fn select_pile(&mut self, view: &PlayerView) -> PileIndex {
+        // Among equally cheap piles clear the longest one since it is the one
+        // most likely to hurt next round.
+        cheapest_pile(view, |cards| std::cmp::Reverse(cards.len()))
+    }
+}
+
+/// Where a card would end up if it were played on the piles as they are now
+enum Landing<'a> {
+    /// On top of this pile
+    Pile(&'a [u8]),
+    /// Lower than every pile so a pile must be selected
+    Below,
+}
+
+fn landing(view: &PlayerView, card: u8) -> Landing<'_> {
+    view.piles
+        .iter()
+        .filter(|p| *p.last().unwrap() < card)
+        .max_by_key(|p| *p.last().unwrap())
+        .map(|p| Landing::Pile(p.as_slice()))
+        .unwrap_or(Landing::Below)
+}
+
+/// A rough measure of how likely playing the card is to cost points
+fn risk(view: &PlayerView, card: u8) -> u32 {
+    match landing(view, card) {
+        Landing::Pile(pile) if pile.len() >= view.rules.pile_capacity => {
+            pile_points(pile) as u32 * 100
+        }
+        Landing::Pile(pile) => {
+            // The bigger the gap, the fuller the pile and the more opponents
+            // there are the more likely it is that other players' cards push
+            // this one into sixth place.
+            let gap = (card - pile.last().unwrap()) as u32;
+            let opponents = view.num_players.saturating_sub(1).max(1) as u32;
+            gap * (pile.len() as u32 + 1) * opponents
+        }
+        Landing::Below => view.piles.iter().map(|p| pile_points(p)).min().unwrap() as u32 * 100,
+    }
+}
+
+/// The pile with the fewest points with ties broken by `tie_break`
+fn cheapest_pile<K: Ord>(view: &PlayerView, tie_break: impl Fn(&[u8]) -> K) -> PileIndex {
+    let (index, _) = view
+        .piles
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, p)| (pile_points(p), tie_break(p)))
+        .unwrap();
+    PileIndex::from(index)
+}
+
+fn pile_points(pile: &[u8]) -> u16 {
+    pile.iter().map(|c| card_points(*c)).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::RuleSet;
+
+    /// A three player view of the round in which the player has `hand` left
+    pub(super) fn view(hand: Vec<u8>, piles: Vec<Vec<u8>>) -> PlayerView {
+        let rules = RuleSet::default();
+        let revealed = piles.iter().flatten().copied().collect();
+        PlayerView {
+            round: (rules.hand_size + 1 - hand.len()) as u8,
+            hand,
+            piles,
+            played: None,
+            must_select_pile: false,
+            revealed,
+            round_plays: Vec::new(),
+            num_players: 3,
+            rules,
+        }
+    }
+
+    #[test]
+    fn test_lowest_risk_avoids_sixth_card() {
+        let view = view(
+            vec![30, 61],
+            vec![vec![10], vec![20, 21, 22, 23, 24], vec![40], vec![60]],
+        );
+        assert_eq!(LowestRiskStrategy.play_card(&view), 61);
+        assert_eq!(LowestRiskStrategy.select_pile(&view), PileIndex::from(0));
+    }
+
+    #[test]
+    fn test_min_bullheads_forces_cheap_pick() {
+        let view = view(
+            vec![3, 61],
+            vec![vec![12], vec![20, 21], vec![40], vec![60]],
+        );
+        assert_eq!(MinBullheadsStrategy.play_card(&view), 3);
+
+        // 2, 3, 4 and 40 are both worth 3 points
+        let view = self::view(
+            vec![1],
+            vec![vec![11, 12], vec![2, 3, 4], vec![40], vec![55]],
+        );
+        assert_eq!(MinBullheadsStrategy.select_pile(&view), PileIndex::from(1));
+        assert_eq!(LowestRiskStrategy.select_pile(&view), PileIndex::from(2));
+    }
+
+    #[test]
+    fn test_timeout_policy() {
+        let mut view = view(
+            vec![30, 61, 7],
+            vec![vec![10], vec![20, 21, 22, 23, 24], vec![40], vec![60]],
+        );
+        assert_eq!(
+            decide(&mut TimeoutPolicy::LowestCard, &view),
+            Move::PlayCard(7)
+        );
+        assert_eq!(
+            decide(&mut TimeoutPolicy::HighestCard, &view),
+            Move::PlayCard(61)
+        );
+        view.must_select_pile = true;
+        assert_eq!(
+            decide(&mut TimeoutPolicy::HighestCard, &view),
+            Move::SelectPile(PileIndex::from(0))
+        );
+    }
+
+    #[test]
+    fn test_random_plays_from_hand() {
+        let view = view(vec![5, 6, 7], vec![vec![10], vec![20], vec![30], vec![40]]);
+        for _ in 0..20 {
+            assert!(view.hand.contains(&RandomStrategy.play_card(&view)));
+        }
+    }
+}
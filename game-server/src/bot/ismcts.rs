@@ -0,0 +1,325 @@
+use rand::{seq::SliceRandom, thread_rng, Rng};
+
+use std::time::{Duration, Instant};
+
+use super::{decide, LowestRiskStrategy, RandomStrategy, Strategy};
+use crate::state::{Move, PileIndex, PlayerView, Simulation, DECK_SIZE};
+
+/// How much the search favours trying moves it knows little about
+const EXPLORATION: f64 = 0.7;
+
+/// Taking this many points or more in a simulated hand counts as the worst outcome
+const WORST_POINTS: f64 = 20.0;
+
+/// How often simulated players make a random move instead of a careful one
+const RANDOM_MOVE_CHANCE: f64 = 0.25;
+
+/// How strong a Monte Carlo bot plays
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Difficulty {
+    Easy,
+    Medium,
+    Hard,
+}
+
+impl Difficulty {
+    pub fn budget(self) -> Budget {
+        match self {
+            Difficulty::Easy => Budget::Iterations(50),
+            Difficulty::Medium => Budget::Iterations(1_000),
+            Difficulty::Hard => Budget::Time(Duration::from_millis(1_500)),
+        }
+    }
+}
+
+/// How much thinking the search may do for a single decision
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Budget {
+    /// Play out this many simulated hands
+    Iterations(u32),
+    /// Play out as many simulated hands as fit in this time
+    Time(Duration),
+}
+
+/// A bot that searches with information set Monte Carlo tree search
+///
+/// Every iteration deals the cards the bot has not seen to its opponents at
+/// random, plays the rest of the hand out with the real game rules and
+/// records how many points the bot took. The tree is built over the bot's own
+/// moves so statistics are shared between all of the sampled deals.
+pub struct IsmctsStrategy {
+    budget: Budget,
+}
+
+impl IsmctsStrategy {
+    pub fn new(budget: Budget) -> Self {
+        Self { budget }
+    }
+
+    fn search(&self, view: &PlayerView) -> Move {
+        let unseen = unseen_cards(view);
+        let mut tree = Tree::new();
+        let started = Instant::now();
+        let mut iterations = 0;
+        while !self.budget_spent(iterations, started) {
+            iterations += 1;
+            if let Some(mut simulation) = determinize(view, &unseen) {
+                tree.iterate(&mut simulation);
+            }
+        }
+        tree.best_move()
+            .unwrap_or_else(|| decide(&mut LowestRiskStrategy, view))
+    }
+
+    fn budget_spent(&self, iterations: u32, started: Instant) -> bool {
+        match self.budget {
+            Budget::Iterations(max) => iterations >= max,
+            Budget::Time(max) => started.elapsed() >= max,
+        }
+    }
+}
+
+impl Strategy for IsmctsStrategy {
+    fn play_card(&mut self, view: &PlayerView) -> u8 {
+        if let [card] = view.hand.as_slice() {
+            return *card;
+        }
+        match self.search(view) {
+            Move::PlayCard(card) => card,
+            Move::SelectPile(_) => unreachable!("searched a card play"),
+        }
+    }
+
+    fn select_pile(&mut self, view: &PlayerView) -> PileIndex {
+        match self.search(view) {
+            Move::SelectPile(pile_index) => pile_index,
+            Move::PlayCard(_) => unreachable!("searched a pile selection"),
+        }
+    }
+}
+
+/// Every card the player can't account for
+fn unseen_cards(view: &PlayerView) -> Vec<u8> {
+    (1..=DECK_SIZE as u8)
+        .filter(|card| {
+            !view.hand.contains(card)
+                && !view.revealed.contains(card)
+                && !view.round_plays.contains(card)
+                && !view.piles.iter().any(|p| p.contains(card))
+        })
+        .collect()
+}
+
+/// Deal the unseen cards to the opponents at random
+///
+/// When the player has to select a pile the cards the opponents played this
+/// round have been revealed, so each opponent gets one of those on top of the
+/// cards dealt to them. Returns `None` if there aren't enough cards to deal.
+fn determinize(view: &PlayerView, unseen: &[u8]) -> Option<Simulation> {
+    let mut rng = thread_rng();
+    let mut unseen = unseen.to_vec();
+    unseen.shuffle(&mut rng);
+
+    let opponents = view.num_players - 1;
+    let hand_size = view.hand.len();
+    let played = view.played.filter(|_| view.must_select_pile);
+    let plays = if played.is_some() {
+        view.round_plays.clone()
+    } else {
+        Vec::new()
+    };
+    let mut hands = Vec::with_capacity(opponents);
+    for i in 0..opponents {
+        let mut hand = Vec::with_capacity(hand_size);
+        hand.extend(plays.get(i));
+        while hand.len() < hand_size {
+            hand.push(unseen.pop()?);
+        }
+        hands.push(hand);
+    }
+
+    let mut simulation = Simulation::new(view, hands);
+    if let Some(played) = played {
+        // Replay the round up to the pile selection
+        simulation.apply_move(Simulation::ME, Move::PlayCard(played));
+        let opponents: Vec<_> = simulation.opponents().collect();
+        for (opponent, card) in opponents.into_iter().zip(plays) {
+            simulation.apply_move(opponent, Move::PlayCard(card));
+        }
+    }
+    Some(simulation)
+}
+
+/// The moves the player could make
+fn legal_moves(view: &PlayerView) -> Vec<Move> {
+    if view.must_select_pile {
+        (0..view.piles.len())
+            .map(|i| Move::SelectPile(PileIndex::from(i)))
+            .collect()
+    } else {
+        view.hand.iter().map(|c| Move::PlayCard(*c)).collect()
+    }
+}
+
+/// The move a player makes once the search has left the tree
+fn rollout_move(view: &PlayerView) -> Move {
+    if thread_rng().gen_bool(RANDOM_MOVE_CHANCE) {
+        decide(&mut RandomStrategy, view)
+    } else {
+        decide(&mut LowestRiskStrategy, view)
+    }
+}
+
+/// The search tree over the player's own moves
+struct Tree {
+    nodes: Vec<Node>,
+}
+
+#[derive(Default)]
+struct Node {
+    visits: u32,
+    /// How often the move leading to this node could have been made
+    availability: u32,
+    reward: f64,
+    children: Vec<(Move, usize)>,
+}
+
+impl Tree {
+    fn new() -> Self {
+        Self {
+            nodes: vec![Node::default()],
+        }
+    }
+
+    /// Play a single simulated hand to the end and record the result
+    fn iterate(&mut self, simulation: &mut Simulation) {
+        let mut path = vec![0];
+        let mut in_tree = true;
+        'hand: loop {
+            let pending = simulation.pending_players();
+            if pending.is_empty() {
+                break;
+            }
+            for player_id in pending {
+                let view = simulation.view_for(player_id);
+                let mv = if player_id != Simulation::ME {
+                    rollout_move(&view)
+                } else if in_tree {
+                    let current = *path.last().unwrap();
+                    let (mv, child, expanded) = self.select(current, &legal_moves(&view));
+                    path.push(child);
+                    in_tree = !expanded;
+                    mv
+                } else {
+                    rollout_move(&view)
+                };
+                if simulation.apply_move(player_id, mv) {
+                    break 'hand;
+                }
+            }
+        }
+
+        let points = simulation.points(Simulation::ME) as f64;
+        let reward = 1.0 - points.min(WORST_POINTS) / WORST_POINTS;
+        for i in path {
+            self.nodes[i].visits += 1;
+            self.nodes[i].reward += reward;
+        }
+    }
+
+    /// Choose the next move from a node, expanding the tree if a move has never been tried
+    ///
+    /// Returns the move, the node it leads to and whether that node is new
+    fn select(&mut self, parent: usize, legal: &[Move]) -> (Move, usize, bool) {
+        let mut untried = legal.to_vec();
+        for (mv, child) in self.nodes[parent].children.clone() {
+            if let Some(i) = untried.iter().position(|m| *m == mv) {
+                untried.swap_remove(i);
+                self.nodes[child].availability += 1;
+            }
+        }
+
+        if let Some(mv) = untried.choose(&mut thread_rng()).copied() {
+            let child = self.nodes.len();
+            self.nodes.push(Node {
+                availability: 1,
+                ..Node::default()
+            });
+            self.nodes[parent].children.push((mv, child));
+            return (mv, child, true);
+        }
+
+        let (mv, child) = self.nodes[parent]
+            .children
+            .iter()
+            .filter(|(mv, _)| legal.contains(mv))
+            .max_by(|(_, a), (_, b)| self.ucb(*a).total_cmp(&self.ucb(*b)))
+            .copied()
+            .unwrap();
+        (mv, child, false)
+    }
+
+    fn ucb(&self, node: usize) -> f64 {
+        let node = &self.nodes[node];
+        let visits = node.visits.max(1) as f64;
+        node.reward / visits
+            + EXPLORATION * ((node.availability.max(1) as f64).ln() / visits).sqrt()
+    }
+
+    /// The most visited move from the root
+    fn best_move(&self) -> Option<Move> {
+        self.nodes[0]
+            .children
+            .iter()
+            .max_by_key(|(_, child)| self.nodes[*child].visits)
+            .map(|(mv, _)| *mv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bot::tests::view;
+
+    #[test]
+    fn test_avoids_sixth_card() {
+        let view = view(
+            vec![30, 61],
+            vec![vec![10], vec![20, 21, 22, 23, 24], vec![40], vec![60]],
+        );
+        let mut bot = IsmctsStrategy::new(Budget::Iterations(200));
+        assert_eq!(bot.play_card(&view), 61);
+    }
+
+    #[test]
+    fn test_selects_cheap_pile() {
+        let mut view = view(
+            vec![1, 90],
+            vec![vec![55], vec![11, 22], vec![40], vec![60]],
+        );
+        view.played = Some(1);
+        view.must_select_pile = true;
+        view.round_plays = vec![41, 95];
+        let mut bot = IsmctsStrategy::new(Budget::Iterations(200));
+        let pile = bot.select_pile(&view);
+        assert!(pile == PileIndex::from(2) || pile == PileIndex::from(3));
+    }
+
+    #[test]
+    fn test_determinize_replays_the_round_when_selecting() {
+        let mut view = view(vec![5, 90], vec![vec![10], vec![20], vec![30], vec![40]]);
+        view.played = Some(5);
+        view.must_select_pile = true;
+        view.round_plays = vec![33, 72];
+        let unseen = unseen_cards(&view);
+        assert_eq!(unseen.len(), DECK_SIZE - 8);
+        for _ in 0..20 {
+            let simulation = determinize(&view, &unseen).unwrap();
+            assert_eq!(simulation.pending_players(), vec![Simulation::ME]);
+            let me = simulation.view_for(Simulation::ME);
+            assert!(me.must_select_pile);
+            assert_eq!(me.round_plays, vec![33, 72]);
+        }
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::history;
+use crate::state::PlayerId;
+
+/// The longest chat message in characters
+const MAX_MESSAGE_LEN: usize = 500;
+
+/// How many messages a room remembers for players who arrive later
+const MAX_HISTORY: usize = 100;
+
+/// How many messages a player may send within `RATE_LIMIT_WINDOW`
+const RATE_LIMIT_MESSAGES: usize = 5;
+
+/// How many reactions a player may send within `RATE_LIMIT_WINDOW`
+const RATE_LIMIT_REACTIONS: usize = 3;
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(10);
+
+/// The reactions players can send
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Emote {
+    Laugh,
+    Facepalm,
+    Cow,
+    Cry,
+    Clap,
+    Wow,
+}
+
+/// A reaction as it is shown to everyone in the room
+///
+/// Reactions are only sent to whoever is connected at the time and are never
+/// kept in the history.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Reaction {
+    pub player_id: PlayerId,
+    pub name: String,
+    pub emote: Emote,
+    /// The player the reaction is aimed at
+    pub target: Option<PlayerId>,
+}
+
+/// A single message sent to the room
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ChatMessage {
+    pub player_id: PlayerId,
+    /// The name of the player at the time they sent the message
+    pub name: String,
+    pub text: String,
+    /// When the message was sent in milliseconds since the Unix epoch
+    pub time: u64,
+}
+
+/// The recent messages of a room
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Chat {
+    /// Oldest first
+    messages: VecDeque<ChatMessage>,
+    #[serde(skip)]
+    messages_sent: RateLimiter,
+    #[serde(skip)]
+    reactions_sent: RateLimiter,
+}
+
+impl Chat {
+    /// Add a message from a player to the history
+    ///
+    /// Returns the message as it is shown to everyone in the room
+    pub fn post(
+        &mut self,
+        player_id: PlayerId,
+        name: String,
+        text: &str,
+        now: Instant,
+    ) -> Result<ChatMessage, ChatError> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(ChatError::Empty);
+        }
+        if text.chars().count() > MAX_MESSAGE_LEN {
+            return Err(ChatError::TooLong);
+        }
+
+        if !self
+            .messages_sent
+            .allow(player_id, now, RATE_LIMIT_MESSAGES)
+        {
+            return Err(ChatError::TooFast);
+        }
+
+        let message = ChatMessage {
+            player_id,
+            name,
+            text: text.to_owned(),
+            time: history::unix_millis(),
+        };
+        self.messages.push_back(message.clone());
+        if self.messages.len() > MAX_HISTORY {
+            self.messages.pop_front();
+        }
+        Ok(message)
+    }
+
+    /// Check a reaction from a player may be sent
+    pub fn react(
+        &mut self,
+        player_id: PlayerId,
+        name: String,
+        emote: Emote,
+        target: Option<PlayerId>,
+        now: Instant,
+    ) -> Result<Reaction, ChatError> {
+        if !self
+            .reactions_sent
+            .allow(player_id, now, RATE_LIMIT_REACTIONS)
+        {
+            return Err(ChatError::TooFast);
+        }
+        Ok(Reaction {
+            player_id,
+            name,
+            emote,
+            target,
+        })
+    }
+
+    /// The messages the room remembers, oldest first
+    pub fn messages(&self) -> &VecDeque<ChatMessage> {
+        &self.messages
+    }
+}
+
+/// When each player sent their latest messages
+#[derive(Debug, Default)]
+struct RateLimiter {
+    sent: HashMap<PlayerId, VecDeque<Instant>>,
+}
+
+impl RateLimiter {
+    /// Record a message from a player unless they already sent `max` messages
+    /// within `RATE_LIMIT_WINDOW`
+    ///
+    /// Returns whether the message may be sent
+    fn allow(&mut self, player_id: PlayerId, now: Instant, max: usize) -> bool {
+        let sent = self.sent.entry(player_id).or_default();
+        while sent
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= RATE_LIMIT_WINDOW)
+        {
+            sent.pop_front();
+        }
+        if sent.len() >= max {
+            return false;
+        }
+        sent.push_back(now);
+        true
+    }
+}
+
+/// Why a chat message or reaction was not sent
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChatError {
+    /// The user is not a player in this room
+    UnknownPlayer,
+    /// The message has nothing but whitespace
+    Empty,
+    TooLong,
+    /// The player sent too many messages in a short time
+    TooFast,
+    /// A reaction was aimed at whoever last took a pile but nobody has this hand
+    NoPileTaken,
+}
+
+impl ChatError {
+    /// A stable machine readable code for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            ChatError::UnknownPlayer => "unknown_player",
+            ChatError::Empty => "empty_chat_message",
+            ChatError::TooLong => "chat_message_too_long",
+            ChatError::TooFast => "chat_rate_limited",
+            ChatError::NoPileTaken => "no_pile_taken",
+        }
+    }
+}
+
+impl std::fmt::Display for ChatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChatError::UnknownPlayer => write!(f, "You are not a player in this room"),
+            ChatError::Empty => write!(f, "Chat messages can't be empty"),
+            ChatError::TooLong => write!(
+                f,
+                "Chat messages can be at most {MAX_MESSAGE_LEN} characters long"
+            ),
+            ChatError::TooFast => write!(f, "You are sending messages too quickly"),
+            ChatError::NoPileTaken => write!(f, "Nobody has taken a pile yet this hand"),
+        }
+    }
+}
+
+impl std::error::Error for ChatError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_post() {
+        let mut chat = Chat::default();
+        let now = Instant::now();
+        let ted = PlayerId::from(1);
+        let message = chat.post(ted, "Ted".to_owned(), "  hello ", now).unwrap();
+        assert_eq!(message.text, "hello");
+        assert_eq!(chat.messages().len(), 1);
+
+        assert_eq!(
+            chat.post(ted, "Ted".to_owned(), " \n ", now),
+            Err(ChatError::Empty)
+        );
+        let long = "a".repeat(MAX_MESSAGE_LEN + 1);
+        assert_eq!(
+            chat.post(ted, "Ted".to_owned(), &long, now),
+            Err(ChatError::TooLong)
+        );
+        assert_eq!(chat.messages().len(), 1);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut chat = Chat::default();
+        let now = Instant::now();
+        let ted = PlayerId::from(1);
+        let bill = PlayerId::from(2);
+        for _ in 0..RATE_LIMIT_MESSAGES {
+            chat.post(ted, "Ted".to_owned(), "hi", now).unwrap();
+        }
+        assert_eq!(
+            chat.post(ted, "Ted".to_owned(), "hi", now),
+            Err(ChatError::TooFast)
+        );
+        // Other players are not affected
+        chat.post(bill, "Bill".to_owned(), "hi", now).unwrap();
+        // Once the window has passed the player may talk again
+        chat.post(ted, "Ted".to_owned(), "hi", now + RATE_LIMIT_WINDOW)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_reactions_are_limited_separately() {
+        let mut chat = Chat::default();
+        let now = Instant::now();
+        let ted = PlayerId::from(1);
+        for _ in 0..RATE_LIMIT_REACTIONS {
+            chat.react(ted, "Ted".to_owned(), Emote::Cow, None, now)
+                .unwrap();
+        }
+        assert_eq!(
+            chat.react(ted, "Ted".to_owned(), Emote::Laugh, None, now),
+            Err(ChatError::TooFast)
+        );
+        chat.post(ted, "Ted".to_owned(), "moo", now).unwrap();
+        assert!(chat.messages().iter().all(|m| m.text == "moo"));
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let mut chat = Chat::default();
+        let start = Instant::now();
+        for i in 0..MAX_HISTORY + 10 {
+            let now = start + RATE_LIMIT_WINDOW * i as u32;
+            chat.post(PlayerId::from(1), "Ted".to_owned(), &i.to_string(), now)
+                .unwrap();
+        }
+        assert_eq!(chat.messages().len(), MAX_HISTORY);
+        assert_eq!(chat.messages()[0].text, "10");
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::state::{PlayerId, TurnId};
+
+/// Each player's bank of thinking time for the hand in progress
+///
+/// A player's bank only runs down while they owe a move. Once it is empty the
+/// player has used up their time for the rest of the hand.
+#[derive(Debug)]
+pub struct TimeBanks {
+    /// The id of the game's log
+    game: u64,
+    /// The hand of that game the banks are for
+    hand: usize,
+    banks: BTreeMap<PlayerId, Bank>,
+    /// When the earliest running bank runs out, if any is running
+    next_expiry: Option<Instant>,
+}
+
+/// The banks as they are written to disk with the room
+///
+/// Banks are saved stopped, so nobody's time runs while the server is down.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SavedBanks {
+    game: u64,
+    hand: usize,
+    remaining_ms: BTreeMap<PlayerId, u64>,
+}
+
+/// What a player's bank shows at a given moment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct Reading {
+    pub remaining_ms: u64,
+    /// Whether the bank is running down right now
+    pub running: bool,
+}
+
+#[derive(Debug)]
+struct Bank {
+    /// What was left when the bank was last stopped
+    remaining: Duration,
+    /// When the bank started running, if the player owes a move
+    running_since: Option<Instant>,
+}
+
+impl Bank {
+    fn remaining(&self, now: Instant) -> Duration {
+        let used = self
+            .running_since
+            .map(|since| now.saturating_duration_since(since))
+            .unwrap_or_default();
+        self.remaining.saturating_sub(used)
+    }
+}
+
+impl TimeBanks {
+    /// Give every player the same bank for the hand `turn` is part of
+    pub fn new(
+        turn: TurnId,
+        players: impl IntoIterator<Item = PlayerId>,
+        budget: Duration,
+    ) -> Self {
+        let banks = players
+            .into_iter()
+            .map(|id| {
+                let bank = Bank {
+                    remaining: budget,
+                    running_since: None,
+                };
+                (id, bank)
+            })
+            .collect();
+        Self {
+            game: turn.game,
+            hand: turn.hand,
+            banks,
+            next_expiry: None,
+        }
+    }
+
+    /// Pick up where banks saved with a room left off
+    pub fn restore(saved: SavedBanks) -> Self {
+        let banks = saved
+            .remaining_ms
+            .into_iter()
+            .map(|(id, ms)| {
+                let bank = Bank {
+                    remaining: Duration::from_millis(ms),
+                    running_since: None,
+                };
+                (id, bank)
+            })
+            .collect();
+        Self {
+            game: saved.game,
+            hand: saved.hand,
+            banks,
+            next_expiry: None,
+        }
+    }
+
+    /// What to save of the banks as they are at `now`
+    pub fn save(&self, now: Instant) -> SavedBanks {
+        SavedBanks {
+            game: self.game,
+            hand: self.hand,
+            remaining_ms: self
+                .readings(now)
+                .into_iter()
+                .map(|(id, reading)| (id, reading.remaining_ms))
+                .collect(),
+        }
+    }
+
+    /// Whether the banks are for the same hand as `turn`
+    pub fn is_for_hand_of(&self, turn: TurnId) -> bool {
+        self.game == turn.game && self.hand == turn.hand
+    }
+
+    /// Run the banks of the players who owe a move and stop everyone else's
+    ///
+    /// Returns when the earliest running bank runs out if that changed.
+    pub fn update(&mut self, owing: &[PlayerId], now: Instant) -> Option<Instant> {
+        for (id, bank) in self.banks.iter_mut() {
+            let owes = owing.contains(id);
+            match bank.running_since {
+                Some(_) if !owes => {
+                    bank.remaining = bank.remaining(now);
+                    bank.running_since = None;
+                }
+                None if owes => bank.running_since = Some(now),
+                _ => {}
+            }
+        }
+        let next_expiry = self
+            .banks
+            .values()
+            .filter(|b| b.running_since.is_some())
+            .map(|b| now + b.remaining(now))
+            .min();
+        if next_expiry == self.next_expiry {
+            return None;
+        }
+        self.next_expiry = next_expiry;
+        next_expiry
+    }
+
+    /// Whether the player has used up their time for this hand
+    pub fn is_empty(&self, player_id: PlayerId, now: Instant) -> bool {
+        self.banks
+            .get(&player_id)
+            .is_some_and(|b| b.remaining(now).is_zero())
+    }
+
+    /// How much time each player has left
+    pub fn readings(&self, now: Instant) -> BTreeMap<PlayerId, Reading> {
+        self.banks
+            .iter()
+            .map(|(id, b)| {
+                let reading = Reading {
+                    remaining_ms: b.remaining(now).as_millis() as u64,
+                    running: b.running_since.is_some(),
+                };
+                (*id, reading)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_banks_only_run_while_owing() {
+        let turn = TurnId {
+            game: 1,
+            hand: 1,
+            round: 1,
+            picker: None,
+        };
+        let ted = PlayerId::from(0);
+        let bill = PlayerId::from(1);
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut banks = TimeBanks::new(turn, [ted, bill], second * 10);
+
+        assert_eq!(banks.update(&[ted, bill], start), Some(start + second * 10));
+        // Bill plays straight away, Ted thinks for a while
+        assert_eq!(banks.update(&[ted], start + second), None);
+        banks.update(&[], start + second * 4);
+        let readings = banks.readings(start + second * 20);
+        assert_eq!(readings[&ted].remaining_ms, 6000);
+        assert_eq!(readings[&bill].remaining_ms, 9000);
+        assert!(!readings[&ted].running);
+
+        // Ted's bank picks up where it left off
+        let later = start + second * 30;
+        assert_eq!(banks.update(&[ted], later), Some(later + second * 6));
+        assert!(!banks.is_empty(ted, later + second * 5));
+        assert!(banks.is_empty(ted, later + second * 6));
+        assert!(!banks.is_empty(bill, later + second * 6));
+    }
+
+    #[test]
+    fn test_restored_banks_keep_their_time() {
+        let turn = TurnId {
+            game: 1,
+            hand: 2,
+            round: 3,
+            picker: None,
+        };
+        let ted = PlayerId::from(0);
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut banks = TimeBanks::new(turn, [ted], second * 10);
+        banks.update(&[ted], start);
+
+        let saved = banks.save(start + second * 4);
+        let restored = TimeBanks::restore(saved.clone());
+        assert_eq!(restored.save(start + second * 60), saved);
+        assert!(restored.is_for_hand_of(TurnId { round: 7, ..turn }));
+        assert!(!restored.is_for_hand_of(TurnId { hand: 3, ..turn }));
+        let readings = restored.readings(start + second * 60);
+        assert_eq!(readings[&ted].remaining_ms, 6000);
+        assert!(!readings[&ted].running);
+    }
+}
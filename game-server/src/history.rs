@@ -0,0 +1,242 @@
+use rand::{thread_rng, Rng};
+
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use crate::state::{card_points, PlayerId, RuleSet};
+
+/// Something that happened during a game
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum GameEvent {
+    /// Every player was dealt a hand and the piles were started
+    Deal {
+        hands: Vec<(PlayerId, Vec<u8>)>,
+        /// The starting card of each pile ordered by pile index
+        piles: Vec<Vec<u8>>,
+    },
+    /// A player chose the card they play this round
+    CardPlayed { player_id: PlayerId, card: u8 },
+    /// A player took back the card they played before it was revealed
+    CardRetracted { player_id: PlayerId, card: u8 },
+    /// Everyone has played so the cards are turned over
+    Reveal {
+        round: u8,
+        /// The cards in the order they will be placed, lowest first
+        cards: Vec<(PlayerId, u8)>,
+    },
+    /// A card was put on the pile it belongs to
+    Placed {
+        player_id: PlayerId,
+        card: u8,
+        pile: usize,
+        /// The cards the player had to take because this was the sixth card
+        taken: Vec<u8>,
+        points: u16,
+    },
+    /// A player whose card was lower than every pile took a pile
+    PileSelected {
+        player_id: PlayerId,
+        card: u8,
+        pile: usize,
+        taken: Vec<u8>,
+        points: u16,
+    },
+    /// The last round of a hand was played
+    HandOver {
+        hand: usize,
+        /// The points each player took in this hand
+        points: Vec<(PlayerId, u16)>,
+    },
+}
+
+impl GameEvent {
+    /// Whether everyone at the table may be told about the event as it happens
+    ///
+    /// Deals and played cards show cards that are still hidden from the other
+    /// players.
+    pub fn is_public(&self) -> bool {
+        match self {
+            GameEvent::Deal { .. }
+            | GameEvent::CardPlayed { .. }
+            | GameEvent::CardRetracted { .. } => false,
+            GameEvent::Reveal { .. }
+            | GameEvent::Placed { .. }
+            | GameEvent::PileSelected { .. }
+            | GameEvent::HandOver { .. } => true,
+        }
+    }
+}
+
+/// Everything that happened in a single game in the order it happened
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct GameLog {
+    /// Tells games apart even when they were played by the same players
+    pub id: u64,
+    pub rules: RuleSet,
+    /// The name of every player in the game
+    pub players: BTreeMap<PlayerId, String>,
+    pub events: Vec<GameEvent>,
+    /// When each event happened in milliseconds since the Unix epoch
+    times: Vec<u64>,
+}
+
+impl GameLog {
+    pub fn new(rules: RuleSet, players: BTreeMap<PlayerId, String>) -> Self {
+        Self {
+            id: thread_rng().gen(),
+            rules,
+            players,
+            events: Vec::new(),
+            times: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, event: GameEvent) {
+        self.events.push(event);
+        self.times.push(unix_millis());
+    }
+
+    /// How many events had happened by the given time in milliseconds since the Unix epoch
+    pub fn events_by(&self, time: u64) -> usize {
+        self.times.partition_point(|t| *t <= time)
+    }
+
+    /// The points a player took in each hand that is over
+    pub fn hand_points(&self, player_id: PlayerId) -> Vec<u16> {
+        self.events
+            .iter()
+            .filter_map(|e| match e {
+                GameEvent::HandOver { points, .. } => Some(
+                    points
+                        .iter()
+                        .find(|(id, _)| *id == player_id)
+                        .map(|(_, p)| *p)
+                        .unwrap_or_default(),
+                ),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The player who most recently took a pile in the current hand
+    pub fn last_pile_taker(&self) -> Option<PlayerId> {
+        self.events
+            .iter()
+            .rev()
+            .take_while(|e| !matches!(e, GameEvent::Deal { .. }))
+            .find_map(|e| match e {
+                GameEvent::Placed {
+                    player_id, taken, ..
+                } if !taken.is_empty() => Some(*player_id),
+                GameEvent::PileSelected { player_id, .. } => Some(*player_id),
+                _ => None,
+            })
+    }
+
+    /// The table as it was right after the event at `step` happened
+    ///
+    /// Returns `None` if the game has no such step
+    pub fn replay(&self, step: usize) -> Option<ReplayStep> {
+        let event = self.events.get(step)?;
+        let mut replay = ReplayStep {
+            step,
+            steps: self.events.len(),
+            event: event.clone(),
+            hand: 0,
+            round: 0,
+            hands: BTreeMap::new(),
+            piles: Vec::new(),
+            played: BTreeMap::new(),
+            points: self.players.keys().map(|id| (*id, 0)).collect(),
+        };
+        for event in &self.events[..=step] {
+            replay.apply(event);
+        }
+        Some(replay)
+    }
+}
+
+/// The current time in milliseconds since the Unix epoch
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// A snapshot of the table part way through a game
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct ReplayStep {
+    pub step: usize,
+    /// How many steps the whole game has
+    pub steps: usize,
+    /// The event that led to this snapshot
+    pub event: GameEvent,
+    pub hand: usize,
+    pub round: u8,
+    pub hands: BTreeMap<PlayerId, Vec<u8>>,
+    /// The cards of each pile ordered by pile index
+    pub piles: Vec<Vec<u8>>,
+    /// The cards played this round which are not on a pile yet
+    pub played: BTreeMap<PlayerId, u8>,
+    pub points: BTreeMap<PlayerId, u16>,
+}
+
+impl ReplayStep {
+    fn apply(&mut self, event: &GameEvent) {
+        match event {
+            GameEvent::Deal { hands, piles } => {
+                self.hand += 1;
+                self.round = 1;
+                self.hands = hands.iter().cloned().collect();
+                self.piles = piles.clone();
+            }
+            GameEvent::CardPlayed { player_id, card } => {
+                self.played.insert(*player_id, *card);
+            }
+            GameEvent::CardRetracted { player_id, .. } => {
+                self.played.remove(player_id);
+            }
+            GameEvent::Reveal { round, .. } => self.round = *round,
+            GameEvent::Placed {
+                player_id,
+                card,
+                pile,
+                taken,
+                ..
+            } => {
+                self.remove_played(*player_id, *card);
+                if taken.is_empty() {
+                    self.piles[*pile].push(*card);
+                } else {
+                    self.take_pile(*player_id, *pile, *card, taken);
+                }
+            }
+            GameEvent::PileSelected {
+                player_id,
+                card,
+                pile,
+                taken,
+                ..
+            } => {
+                self.remove_played(*player_id, *card);
+                self.take_pile(*player_id, *pile, *card, taken);
+            }
+            GameEvent::HandOver { .. } => {}
+        }
+    }
+
+    fn remove_played(&mut self, player_id: PlayerId, card: u8) {
+        self.played.remove(&player_id);
+        if let Some(hand) = self.hands.get_mut(&player_id) {
+            hand.retain(|c| *c != card);
+        }
+    }
+
+    fn take_pile(&mut self, player_id: PlayerId, pile: usize, card: u8, taken: &[u8]) {
+        self.piles[pile] = vec![card];
+        *self.points.entry(player_id).or_default() +=
+            taken.iter().map(|c| card_points(*c)).sum::<u16>();
+    }
+}
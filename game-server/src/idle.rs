@@ -0,0 +1,199 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::state::{PlayerId, TurnId};
+
+/// When players who are connected but don't move count as away from the keyboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct AfkThresholds {
+    /// Seconds a player may owe a move without doing anything before they are
+    /// shown as away
+    pub away_secs: u64,
+    /// Seconds after which a bot plays for the player until they send a message
+    pub takeover_secs: u64,
+}
+
+/// Which connected players have gone quiet while they owe a move
+#[derive(Debug, Default)]
+pub struct IdleTracker {
+    /// When each connected player last did something or was asked to move
+    idle_since: HashMap<PlayerId, Instant>,
+    /// The turn connected players were last asked to move on
+    turn: Option<TurnId>,
+    /// Connected players who owe a move but haven't done anything for a while
+    away: HashSet<PlayerId>,
+    /// Away players a bot plays for until they send a message
+    taken_over: HashSet<PlayerId>,
+    /// When the next player becomes away or gets taken over, if anyone will
+    next_check: Option<Instant>,
+}
+
+impl IdleTracker {
+    /// Start watching a player who just connected
+    pub fn connected(&mut self, player_id: PlayerId, now: Instant) {
+        self.idle_since.insert(player_id, now);
+        self.away.remove(&player_id);
+        self.taken_over.remove(&player_id);
+    }
+
+    /// Stop watching a player whose socket is gone
+    pub fn disconnected(&mut self, player_id: PlayerId) {
+        self.idle_since.remove(&player_id);
+    }
+
+    /// Carry on watching a connected player under the id of the seat they took
+    pub fn moved(&mut self, from: PlayerId, to: PlayerId) {
+        if let Some(since) = self.idle_since.remove(&from) {
+            self.idle_since.insert(to, since);
+        }
+    }
+
+    /// Note that a player did something, handing control back to them
+    ///
+    /// Returns whether the player had been away.
+    pub fn touch(&mut self, player_id: PlayerId, now: Instant) -> bool {
+        if let Some(since) = self.idle_since.get_mut(&player_id) {
+            *since = now;
+        }
+        self.taken_over.remove(&player_id);
+        self.away.remove(&player_id)
+    }
+
+    /// Forget about anyone being away, for when AFK detection is off
+    pub fn clear(&mut self) {
+        self.away.clear();
+        self.taken_over.clear();
+        self.next_check = None;
+    }
+
+    pub fn is_away(&self, player_id: PlayerId) -> bool {
+        self.away.contains(&player_id)
+    }
+
+    pub fn taken_over(&self) -> &HashSet<PlayerId> {
+        &self.taken_over
+    }
+
+    /// When the tracker next wants to be updated, if anyone will go idle
+    pub fn next_check(&self) -> Option<Instant> {
+        self.next_check
+    }
+
+    /// Flag the connected players who have owed a move for too long
+    ///
+    /// Returns whether anyone was newly taken over, and when to check again if
+    /// that changed.
+    pub fn update(
+        &mut self,
+        turn: Option<TurnId>,
+        owing: &[PlayerId],
+        afk: AfkThresholds,
+        now: Instant,
+    ) -> (bool, Option<Instant>) {
+        if turn != self.turn {
+            self.turn = turn;
+            // Waiting for the others to move doesn't count as being idle
+            for id in owing {
+                if let Some(since) = self.idle_since.get_mut(id) {
+                    *since = now;
+                }
+            }
+        }
+        if turn.is_none() {
+            self.taken_over.clear();
+        }
+
+        let away_after = Duration::from_secs(afk.away_secs);
+        let takeover_after = Duration::from_secs(afk.takeover_secs);
+        let mut newly_taken_over = false;
+        let mut next_check: Option<Instant> = None;
+        // Players stay away while a bot plays for them
+        let mut away = self.taken_over.clone();
+        for id in owing {
+            let Some(&since) = self.idle_since.get(id) else {
+                continue;
+            };
+            if self.taken_over.contains(id) {
+                continue;
+            }
+            let idle = now.saturating_duration_since(since);
+            let check = if idle >= takeover_after {
+                self.taken_over.insert(*id);
+                newly_taken_over = true;
+                away.insert(*id);
+                continue;
+            } else if idle >= away_after {
+                away.insert(*id);
+                since + takeover_after
+            } else {
+                since + away_after
+            };
+            next_check = Some(next_check.map_or(check, |c| c.min(check)));
+        }
+        self.away = away;
+
+        if next_check == self.next_check {
+            return (newly_taken_over, None);
+        }
+        self.next_check = next_check;
+        (newly_taken_over, next_check)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idle_players_go_away_then_get_taken_over() {
+        let turn = TurnId {
+            game: 1,
+            hand: 1,
+            round: 1,
+            picker: None,
+        };
+        let ted = PlayerId::from(0);
+        let bill = PlayerId::from(1);
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let afk = AfkThresholds {
+            away_secs: 10,
+            takeover_secs: 30,
+        };
+        let mut idle = IdleTracker::default();
+        idle.connected(ted, start);
+        idle.connected(bill, start);
+
+        // Being asked to move starts the clock for everyone who owes a move
+        let asked = start + second * 5;
+        let (taken_over, next) = idle.update(Some(turn), &[ted, bill], afk, asked);
+        assert!(!taken_over);
+        assert_eq!(next, Some(asked + second * 10));
+        // Nothing changed, so there is no new check to schedule
+        assert_eq!(
+            idle.update(Some(turn), &[ted, bill], afk, asked),
+            (false, None)
+        );
+
+        // Bill sends a message, Ted doesn't
+        assert!(!idle.touch(bill, asked + second * 8));
+        let (taken_over, next) = idle.update(Some(turn), &[ted, bill], afk, asked + second * 10);
+        assert!(!taken_over);
+        assert_eq!(next, Some(asked + second * 18));
+        assert!(idle.is_away(ted));
+        assert!(!idle.is_away(bill));
+
+        let (taken_over, _) = idle.update(Some(turn), &[ted, bill], afk, asked + second * 30);
+        assert!(taken_over);
+        assert!(idle.taken_over().contains(&ted));
+        assert!(idle.is_away(bill));
+
+        // Ted stays away while the bot plays for them, until they are back
+        let (taken_over, _) = idle.update(Some(turn), &[bill], afk, asked + second * 31);
+        assert!(!taken_over);
+        assert!(idle.is_away(ted));
+        assert!(idle.touch(ted, asked + second * 32));
+        assert!(!idle.is_away(ted));
+        assert!(idle.taken_over().is_empty());
+    }
+}
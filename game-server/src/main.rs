@@ -1,22 +1,32 @@
 use axum::extract::connect_info::ConnectInfo;
 use axum::extract::ws::{self, WebSocket, WebSocketUpgrade};
 use axum::extract::Query;
+use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::routing::{get, post};
 use axum::{Json, Router};
-use futures::stream::{SplitSink, StreamExt};
-use futures::SinkExt;
-use once_cell::sync::OnceCell;
-use tokio::sync::Mutex;
+use futures::stream::StreamExt;
 use tower_http::services::ServeDir;
 
-use std::collections::{HashMap, HashSet};
 use std::ops::ControlFlow;
+use std::sync::Arc;
 use std::{net::SocketAddr, path::PathBuf};
 
+mod bot;
+mod chat;
+mod clock;
+mod history;
+mod idle;
+mod room;
 mod state;
+mod vote;
 
-use state::{PileIndex, State};
+use bot::StrategyKind;
+use chat::{ChatError, Emote};
+use idle::AfkThresholds;
+use room::{AbandonPolicy, ChessClock, Room, SpectatorDelay, TurnTimer};
+use state::{PileIndex, PlayerId, RuleSet, State, StateError};
+use vote::{Majority, VoteKind};
 
 #[tokio::main]
 async fn main() {
@@ -24,10 +34,15 @@ async fn main() {
         .join("..")
         .join("dist");
 
+    let loaded = room::load_saved().await;
+    println!("Loaded {loaded} saved rooms");
+    tokio::spawn(room::evict_idle_rooms());
+
     // build our application with some routes
     let app = Router::new()
         .fallback_service(ServeDir::new(assets_dir).append_index_html_on_directories(true))
         .route("/join", post(join))
+        .route("/replay", get(replay))
         .route("/version", get(version))
         .route("/ws", get(ws_handler));
 
@@ -40,101 +55,335 @@ async fn main() {
 }
 
 #[derive(serde::Deserialize)]
-struct Name {
+struct JoinRequest {
     name: String,
+    room: String,
+    /// Join the waiting list if there is no seat free right now
+    #[serde(default)]
+    wait: bool,
 }
 
-async fn version() -> impl IntoResponse {
-    format!("{}", get_version())
+#[derive(serde::Deserialize)]
+struct VersionParams {
+    room: String,
+}
+
+/// The version of a room, which changes if the room the client joined is gone
+async fn version(Query(VersionParams { room }): Query<VersionParams>) -> impl IntoResponse {
+    let room = match room::normalize_code(&room) {
+        Some(code) => room::get(&code).await,
+        None => None,
+    };
+    match room {
+        Some(room) => room.version().to_string().into_response(),
+        None => (StatusCode::NOT_FOUND, "room not found").into_response(),
+    }
 }
 
 async fn join(
     ConnectInfo(who): ConnectInfo<SocketAddr>,
-    Json(Name { name }): Json<Name>,
+    Json(JoinRequest { name, room, wait }): Json<JoinRequest>,
 ) -> impl IntoResponse {
-    println!("{who} attempting to join...");
-    let user_id = state().lock().await.join(name);
-    match user_id {
-        Ok(user_id) => {
-            println!("{who} joined lobby with user_id: {user_id}");
-            broadcast_state().await;
+    println!("{who} attempting to join room '{room}'...");
+    let Some(code) = room::normalize_code(&room) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(error_json(
+                "invalid_room_code",
+                &format!("'{room}' is not a valid room code"),
+            )),
+        )
+            .into_response();
+    };
+    let room = room::get_or_create(&code).await;
+    let joined = {
+        let mut state = room.state.lock().await;
+        // Players who arrive once the game has started wait for the next one
+        match state.join(name.clone()) {
+            Err(StateError::GameAlreadyStarted | StateError::GameOver) => {
+                state.wait(name).map(|joined| (joined, true))
+            }
+            Err(StateError::TableFull) if wait => state.wait(name).map(|joined| (joined, true)),
+            joined => joined.map(|joined| (joined, false)),
+        }
+    };
+    match joined {
+        Ok(((token, player_id), waiting)) => {
+            if waiting {
+                println!("{who} is waiting for a seat in room '{code}' as player {player_id}");
+            } else {
+                println!("{who} joined lobby of room '{code}' as player {player_id}");
+            }
+            room.state_changed().await;
             Json(serde_json::json! {
                 {
-                    "user_id": user_id,
-                    "version": get_version()
+                    "token": token,
+                    "player_id": player_id,
+                    "room": code,
+                    "version": room.version(),
+                    "waiting": waiting
                 }
             })
+            .into_response()
+        }
+        Err(e) => {
+            println!("{who} could not join room '{code}': {e}");
+            (
+                join_error_status(&e),
+                Json(error_json(e.code(), &e.to_string())),
+            )
+                .into_response()
         }
-        Err(_) => todo!("Game already begun"),
     }
 }
 
+/// The HTTP status to reply with when a player can't join a room
+fn join_error_status(e: &StateError) -> StatusCode {
+    match e {
+        StateError::GameAlreadyStarted
+        | StateError::GameOver
+        | StateError::NameTaken
+        | StateError::TableFull => StatusCode::CONFLICT,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+/// The body sent to clients whenever a request of theirs fails
+fn error_json(code: &str, message: &str) -> serde_json::Value {
+    serde_json::json!({
+        "event": "error",
+        "code": code,
+        "message": message,
+    })
+}
+
+#[derive(serde::Deserialize)]
+struct ReplayParams {
+    room: String,
+    /// Which finished game to show counting from 0, defaults to the latest
+    game: Option<usize>,
+    /// Show the table after this event instead of the whole log
+    step: Option<usize>,
+}
+
+/// Look back at a finished game, either as a whole or one event at a time
+async fn replay(
+    Query(ReplayParams { room, game, step }): Query<ReplayParams>,
+) -> impl IntoResponse {
+    let room = match room::normalize_code(&room) {
+        Some(code) => room::get(&code).await,
+        None => None,
+    };
+    let Some(room) = room else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(error_json(
+                "room_not_found",
+                "There is no room with that code",
+            )),
+        )
+            .into_response();
+    };
+    let finished_games = room.finished_games().await;
+    let game = game.unwrap_or(finished_games.len().saturating_sub(1));
+    let Some(log) = finished_games.get(game) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(error_json(
+                "game_not_found",
+                "No game with that number has finished in this room",
+            )),
+        )
+            .into_response();
+    };
+    let Some(step) = step else {
+        return Json(serde_json::json!({
+            "game": game,
+            "games": finished_games.len(),
+            "log": log,
+        }))
+        .into_response();
+    };
+    match log.replay(step) {
+        Some(replay) => Json(serde_json::json!(replay)).into_response(),
+        None => (
+            StatusCode::BAD_REQUEST,
+            Json(error_json(
+                "invalid_step",
+                &format!("The game only has {} steps", log.events.len()),
+            )),
+        )
+            .into_response(),
+    }
+}
+
+/// Players connect with their session token, spectators with just a name
 #[derive(serde::Deserialize)]
-struct UserId {
-    user_id: String,
+struct WsParams {
+    room: String,
+    token: Option<String>,
+    spectate: Option<String>,
 }
 
 async fn ws_handler(
     ws: WebSocketUpgrade,
-    Query(UserId { user_id }): Query<UserId>,
+    Query(WsParams {
+        room,
+        token,
+        spectate,
+    }): Query<WsParams>,
     ConnectInfo(who): ConnectInfo<SocketAddr>,
 ) -> impl IntoResponse {
-    println!("{who} connected to WebSocket with user_id '{user_id}'.");
-    ws.on_upgrade(move |socket| handle_socket(socket, who, user_id))
+    println!("{who} connected to WebSocket for room '{room}'.");
+    ws.on_upgrade(move |socket| handle_socket(socket, who, room, token, spectate))
 }
 
 /// Actual websocket statemachine (one will be spawned per connection)
-async fn handle_socket(mut socket: WebSocket, who: SocketAddr, user_id: String) {
-    if state().lock().await.get_player(&user_id).is_none() {
+async fn handle_socket(
+    socket: WebSocket,
+    who: SocketAddr,
+    room: String,
+    token: Option<String>,
+    spectate: Option<String>,
+) {
+    let room = match room::normalize_code(&room) {
+        Some(code) => room::get(&code).await,
+        None => None,
+    };
+    let Some(room) = room else {
+        println!("{who} connected to a room that does not exist. Closing connection.");
+        close_with_policy(socket, "room not found").await;
+        return;
+    };
+    match (token, spectate) {
+        (Some(token), _) => handle_player(socket, who, room, token).await,
+        (None, Some(name)) => handle_spectator(socket, who, room, name).await,
+        (None, None) => close_with_policy(socket, "session token or spectator name required").await,
+    }
+}
+
+async fn handle_player(socket: WebSocket, who: SocketAddr, room: Arc<Room>, token: String) {
+    let player_id = room.state.lock().await.player_for_token(&token);
+    let Some(player_id) = player_id else {
         println!(
-            "{who} connected with user_id '{user_id}', but that user_id is not in the game. Closing connection."
+            "{who} connected with a session token that is not in room '{}'. Closing connection.",
+            room.code()
         );
-        socket
-            .send(ws::Message::Close(Some(ws::CloseFrame {
-                code: ws::close_code::POLICY,
-                reason: "user_id not in game".into(),
-            })))
-            .await
-            .unwrap();
+        close_with_policy(socket, "session token not in game").await;
         return;
-    }
+    };
+    println!("{who} authenticated as player {player_id}.");
     let (sender, mut receiver) = socket.split();
-    {
-        let mut senders = senders().lock().await;
-        senders.authenticated.insert(user_id.clone(), sender);
+    let connection_id = room.add_sender(player_id, sender).await;
+    room.send_state(player_id).await;
+    let recv = {
+        let room = room.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+            while let Some(Ok(msg)) = receiver.next().await {
+                // The token points at another seat once a waiting player takes one over
+                let player_id = room.state.lock().await.player_for_token(&token);
+                let Some(player_id) = player_id else {
+                    return;
+                };
+                if process_message(&room, msg, who, player_id).await.is_break() {
+                    return;
+                }
+            }
+        })
+    };
+    if let Err(e) = recv.await {
+        eprintln!("Error in socket task for {who}: {e}");
     }
-    send_state(&user_id).await;
-    let recv = tokio::spawn(async move {
-        while let Some(Ok(msg)) = receiver.next().await {
-            if process_message(msg, who, &user_id).await.is_break() {
-                return;
+    let player_id = room
+        .state
+        .lock()
+        .await
+        .player_for_token(&token)
+        .unwrap_or(player_id);
+    room.disconnect(player_id, connection_id).await;
+}
+
+async fn handle_spectator(socket: WebSocket, who: SocketAddr, room: Arc<Room>, name: String) {
+    let Ok(name) = state::normalize_name(&name) else {
+        println!("{who} tried to spectate with an invalid name. Closing connection.");
+        close_with_policy(socket, "invalid spectator name").await;
+        return;
+    };
+    println!("{who} is spectating room '{}' as {name}.", room.code());
+    let (sender, mut receiver) = socket.split();
+    let connection_id = room.add_spectator(name, sender).await;
+    room.welcome_spectator(connection_id).await;
+    while let Some(Ok(msg)) = receiver.next().await {
+        match msg {
+            ws::Message::Text(t) => {
+                println!(">>> spectator {who} sent str: {t:?}");
+                let error = error_json("spectator", "Spectators can't take part in the game");
+                room.send_to_spectator(connection_id, error.to_string())
+                    .await;
             }
+            ws::Message::Close(_) => break,
+            _ => {}
         }
-    });
-    recv.await.unwrap();
+    }
+    room.remove_spectator(connection_id).await;
 }
 
-fn state() -> &'static Mutex<State> {
-    static STATE: OnceCell<Mutex<State>> = OnceCell::new();
-    STATE.get_or_init(|| Mutex::new(State::new()))
+async fn close_with_policy(mut socket: WebSocket, reason: &'static str) {
+    if let Err(e) = socket
+        .send(ws::Message::Close(Some(ws::CloseFrame {
+            code: ws::close_code::POLICY,
+            reason: reason.into(),
+        })))
+        .await
+    {
+        eprintln!("Error closing socket: {e}");
+    }
 }
 
 /// helper to print contents of messages to stdout. Has special treatment for Close.
-async fn process_message(msg: ws::Message, who: SocketAddr, user_id: &str) -> ControlFlow<(), ()> {
+async fn process_message(
+    room: &Arc<Room>,
+    msg: ws::Message,
+    who: SocketAddr,
+    player_id: PlayerId,
+) -> ControlFlow<(), ()> {
     match msg {
         ws::Message::Text(t) => {
             println!(">>> {} sent str: {:?}", who, t);
-            let msg: Message = match serde_json::from_str(&t) {
+            // Any message brings a player back from being away
+            if room.touch(player_id).await {
+                room.broadcast_state().await;
+            }
+            let msg = match parse_message(&t) {
                 Ok(m) => m,
                 Err(e) => {
                     eprintln!("Error parsing '{t}': {e}");
+                    let error = error_json("invalid_message", &e.to_string());
+                    room.send_message(player_id, error.to_string()).await;
                     return ControlFlow::Continue(());
                 }
             };
 
-            match handle_message(msg, user_id).await {
-                Ok(()) => broadcast_state().await,
-                Err(e) => send_message(user_id, e).await,
+            let msg = match msg {
+                Incoming::Chat(chat) => {
+                    if let Err(e) = handle_chat_request(room, chat, player_id).await {
+                        println!(">>> {who} sent a chat message that was rejected: {e}");
+                        let error = error_json(e.code(), &e.to_string());
+                        room.send_message(player_id, error.to_string()).await
+                    }
+                    return ControlFlow::Continue(());
+                }
+                Incoming::Game(msg) => msg,
+            };
+
+            match handle_message(room, msg, player_id).await {
+                Ok(()) => room.state_changed().await,
+                Err(e) => {
+                    println!(">>> {who} sent a message that was rejected: {e}");
+                    let error = error_json(e.code(), &e.to_string());
+                    room.send_message(player_id, error.to_string()).await
+                }
             }
         }
         ws::Message::Close(Some(cf)) => {
@@ -142,12 +391,10 @@ async fn process_message(msg: ws::Message, who: SocketAddr, user_id: &str) -> Co
                 ">>> {} sent close with code {} and reason `{}`",
                 who, cf.code, cf.reason
             );
-            remove_sender(user_id).await;
             return ControlFlow::Break(());
         }
         ws::Message::Close(None) => {
             println!(">>> {} sent close message without CloseFrame", who);
-            remove_sender(user_id).await;
             return ControlFlow::Break(());
         }
 
@@ -158,118 +405,291 @@ async fn process_message(msg: ws::Message, who: SocketAddr, user_id: &str) -> Co
     ControlFlow::Continue(())
 }
 
-fn senders() -> &'static Mutex<Senders> {
-    static SENDERS: OnceCell<Mutex<Senders>> = OnceCell::new();
-    SENDERS.get_or_init(|| Mutex::new(Senders::new()))
-}
-
-struct Senders {
-    authenticated: HashMap<String, SplitSink<WebSocket, ws::Message>>,
-}
-
-impl Senders {
-    fn new() -> Self {
-        Self {
-            authenticated: HashMap::new(),
-        }
-    }
-
-    fn online_users(&self) -> HashSet<String> {
-        self.authenticated.keys().cloned().collect()
-    }
-}
-
-async fn remove_sender(user_id: &str) {
-    senders().lock().await.authenticated.remove(user_id);
-    broadcast_state().await;
-}
-
-async fn send_message(user_id: &str, msg: String) {
-    senders()
-        .lock()
-        .await
-        .authenticated
-        .get_mut(user_id)
-        .unwrap()
-        .send(ws::Message::Text(msg))
-        .await
-        .unwrap();
-}
-
-async fn broadcast_state() {
-    let state = state().lock().await;
-    let mut senders = senders().lock().await;
-    let online_users = senders.online_users();
-    for (user_id, sender) in senders.authenticated.iter_mut() {
-        _send_state(&state, user_id, &online_users, sender).await;
-    }
-}
-
-async fn send_state(user_id: &str) {
-    let state = state().lock().await;
-    let mut senders = senders().lock().await;
-    let online_users = senders.online_users();
-    if let Some(sender) = senders.authenticated.get_mut(user_id) {
-        _send_state(&state, user_id, &online_users, sender).await;
+async fn handle_chat_request(
+    room: &Arc<Room>,
+    msg: ChatRequest,
+    player_id: PlayerId,
+) -> Result<(), ChatError> {
+    match msg {
+        ChatRequest::Chat { text } => room.post_chat(player_id, &text).await,
+        ChatRequest::React {
+            emote,
+            at_pile_taker,
+        } => room.react(player_id, emote, at_pile_taker).await,
     }
 }
 
-async fn _send_state(
-    state: &State,
-    user_id: &str,
-    online_users: &HashSet<String>,
-    sender: &mut SplitSink<WebSocket, ws::Message>,
-) {
-    let response = state.serialize_for_user(user_id, online_users);
-    if let Err(e) = sender
-        .send(ws::Message::Text(serde_json::to_string(&response).unwrap()))
-        .await
-    {
-        eprintln!("Error sending broadcast: {e}");
+async fn handle_message(
+    room: &Arc<Room>,
+    msg: Message,
+    player_id: PlayerId,
+) -> Result<(), StateError> {
+    let mut state = room.state.lock().await;
+    // Players on the waiting list have no seat to play from yet
+    if state.get_player(player_id).is_none() && !matches!(msg, Message::ClaimSeat { .. }) {
+        return Err(StateError::UnknownPlayer);
     }
-}
-
-async fn handle_message(msg: Message, user_id: &str) -> Result<(), String> {
-    let mut state = state().lock().await;
-    if state.get_player(user_id).is_none() {
-        return Err(format!("Player with id '{user_id}' does not exist"));
+    if msg.host_only() && state.host() != Some(player_id) {
+        return Err(StateError::NotHost);
     }
     match msg {
         Message::Debug => {
             println!("{state:#?}")
         }
         Message::StartGame => {
-            state.start_game().unwrap();
-        }
-        Message::PlayCard { card } => {
-            state.play_card(user_id, card).unwrap();
+            state.can_start()?;
+            room.start_countdown(true).await;
         }
+        Message::SetReady { ready } => state.set_ready(player_id, ready)?,
+        Message::PlayCard { card } => state.play_card(player_id, card)?,
+        Message::RetractCard => state.retract_card(player_id)?,
         Message::SelectPile { pile_index } => {
-            let pile_index = PileIndex::try_from(pile_index).expect("TODO: handle error");
-            state.select_pile(user_id, pile_index).unwrap();
+            state.select_pile(player_id, PileIndex::from(pile_index))?
+        }
+        // Abandoning a game in progress takes a vote but the host decides on a rematch
+        Message::RestartGame => match &*state {
+            State::Lobby(_) => {}
+            State::Game(_) => {
+                room.start_vote(&state, VoteKind::Restart, player_id)
+                    .await?
+            }
+            State::GameOver(..) if state.host() != Some(player_id) => {
+                return Err(StateError::NotHost)
+            }
+            State::GameOver(..) => state.rematch()?,
+        },
+        Message::ReturnToLobby => state.return_to_lobby()?,
+        Message::PauseGame => pause(room, &mut state, player_id, true).await?,
+        Message::ResumeGame => pause(room, &mut state, player_id, false).await?,
+        Message::ClaimSeat { player_id: seat } => {
+            if !state.is_waiting(player_id) {
+                return Err(StateError::NotWaiting);
+            }
+            if !room.is_abandoned(seat).await {
+                return Err(StateError::SeatNotAbandoned);
+            }
+            let kind = VoteKind::TakeSeat { seat, player_id };
+            room.start_vote(&state, kind, player_id).await?
+        }
+        Message::Vote { yes } => room.cast_vote(player_id, yes).await?,
+        Message::UpdateRules { rules } => state.set_rules(rules)?,
+        Message::AddBot { strategy } => {
+            state.add_bot(strategy)?;
+        }
+        Message::RemoveBot { player_id } => state.remove_bot(player_id)?,
+        Message::SubstituteBot {
+            player_id,
+            strategy,
+        } => {
+            if !room.is_abandoned(player_id).await {
+                return Err(StateError::SeatNotAbandoned);
+            }
+            state.set_stand_in(player_id, Some(strategy))?;
+        }
+        Message::KickPlayer { player_id: kicked } => {
+            state.kick(player_id, kicked)?;
+            room.close_connection(kicked, "kicked from the room").await;
+        }
+        Message::TransferHost { player_id } => state.set_host(player_id)?,
+        Message::UpdateSettings {
+            grace_period_secs,
+            abandon_policy,
+            spectator_delay,
+            vote_majority,
+            turn_timer,
+            chess_clock,
+            afk,
+            reveal_step_ms,
+        } => {
+            let mut settings = room.settings.lock().await;
+            if let Some(grace_period_secs) = grace_period_secs {
+                settings.grace_period_secs = grace_period_secs;
+            }
+            if let Some(abandon_policy) = abandon_policy {
+                settings.abandon_policy = abandon_policy;
+            }
+            if let Some(delay) = spectator_delay {
+                settings.spectator_delay = Some(delay).filter(|d| !d.is_zero());
+            }
+            if let Some(vote_majority) = vote_majority {
+                settings.vote_majority = vote_majority;
+            }
+            if let Some(timer) = turn_timer {
+                settings.turn_timer = Some(timer).filter(|t| !t.is_off());
+            }
+            if let Some(clock) = chess_clock {
+                settings.chess_clock = Some(clock).filter(|c| c.bank_secs > 0);
+            }
+            if let Some(afk) = afk {
+                let afk = AfkThresholds {
+                    // Nobody can be taken over before they are shown as away
+                    takeover_secs: afk.takeover_secs.max(afk.away_secs),
+                    ..afk
+                };
+                settings.afk = Some(afk).filter(|a| a.away_secs > 0);
+            }
+            if let Some(step) = reveal_step_ms {
+                settings.reveal_step_ms = Some(step).filter(|s| *s > 0);
+            }
         }
-        Message::RestartGame => state.restart(),
     }
     Ok(())
 }
 
+/// Pause or resume the game, asking the other players to vote unless the host asked
+async fn pause(
+    room: &Arc<Room>,
+    state: &mut State,
+    player_id: PlayerId,
+    paused: bool,
+) -> Result<(), StateError> {
+    if state.host() == Some(player_id) {
+        return state.set_paused(paused);
+    }
+    state.can_pause(paused)?;
+    let kind = if paused {
+        VoteKind::Pause
+    } else {
+        VoteKind::Resume
+    };
+    room.start_vote(state, kind, player_id).await
+}
+
+/// A message from a player, which the room handles apart from the game if it
+/// is only chat
+enum Incoming {
+    Chat(ChatRequest),
+    Game(Message),
+}
+
+/// Read a message from a player, going by its event to tell chat from moves
+/// in the game
+fn parse_message(text: &str) -> Result<Incoming, serde_json::Error> {
+    use serde::Deserialize;
+    let value: serde_json::Value = serde_json::from_str(text)?;
+    match value.get("event").and_then(|e| e.as_str()) {
+        Some("chat" | "react") => ChatRequest::deserialize(value).map(Incoming::Chat),
+        _ => Message::deserialize(value).map(Incoming::Game),
+    }
+}
+
+/// Messages that only go to the room's chat and never change the game
+#[derive(serde::Deserialize)]
+#[serde(tag = "event")]
+#[serde(rename_all = "snake_case")]
+enum ChatRequest {
+    /// Say something to everyone in the room
+    Chat { text: String },
+    /// Send a reaction to everyone in the room
+    React {
+        emote: Emote,
+        /// Aim the reaction at whoever last took a pile
+        #[serde(default)]
+        at_pile_taker: bool,
+    },
+}
+
 #[derive(serde::Deserialize)]
 #[serde(tag = "event")]
 #[serde(rename_all = "snake_case")]
 enum Message {
     Debug,
+    /// Start the game in a few seconds whether everyone is ready or not
     StartGame,
-    PlayCard { card: u8 },
-    SelectPile { pile_index: usize },
+    /// Say whether the player is ready for the game to start
+    SetReady {
+        ready: bool,
+    },
+    PlayCard {
+        card: u8,
+    },
+    /// Take back the card played this round while others are still choosing
+    RetractCard,
+    SelectPile {
+        pile_index: usize,
+    },
+    /// Vote to abandon the game in progress, or start a rematch once it is over
     RestartGame,
+    /// Pause the game in progress, straight away for the host and by vote for
+    /// everyone else
+    PauseGame,
+    /// Resume a paused game, straight away for the host and by vote for
+    /// everyone else
+    ResumeGame,
+    /// Go back to the lobby once the game is over
+    ReturnToLobby,
+    /// Ask the players to let a waiting player take over an abandoned seat
+    ClaimSeat {
+        player_id: PlayerId,
+    },
+    /// Vote on the question the players are being asked
+    Vote {
+        yes: bool,
+    },
+    /// Choose the rules for the next game
+    UpdateRules {
+        rules: RuleSet,
+    },
+    AddBot {
+        strategy: StrategyKind,
+    },
+    RemoveBot {
+        player_id: PlayerId,
+    },
+    /// Let a bot play for a player whose seat has been abandoned
+    SubstituteBot {
+        player_id: PlayerId,
+        strategy: StrategyKind,
+    },
+    /// Remove a player from the lobby
+    KickPlayer {
+        player_id: PlayerId,
+    },
+    /// Make another player the host
+    TransferHost {
+        player_id: PlayerId,
+    },
+    UpdateSettings {
+        grace_period_secs: Option<u64>,
+        abandon_policy: Option<AbandonPolicy>,
+        /// A delay of zero turns the delay off
+        spectator_delay: Option<SpectatorDelay>,
+        vote_majority: Option<Majority>,
+        /// A timer with no limit on either kind of turn turns the timer off
+        turn_timer: Option<TurnTimer>,
+        /// A bank of zero seconds turns the chess clock off
+        chess_clock: Option<ChessClock>,
+        /// An away threshold of zero turns AFK detection off, and a takeover
+        /// threshold below it is raised to match
+        afk: Option<AfkThresholds>,
+        /// Zero sends the steps of a reveal without waiting between them
+        reveal_step_ms: Option<u64>,
+    },
 }
 
-fn get_version() -> u64 {
-    static STARTUP_TIME: OnceCell<u64> = OnceCell::new();
-    *STARTUP_TIME.get_or_init(|| {
-        std::time::SystemTime::now()
-            .duration_since(std::time::SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-    })
+impl Message {
+    /// Whether only the host of the room may send this message
+    fn host_only(&self) -> bool {
+        match self {
+            Message::StartGame
+            | Message::ReturnToLobby
+            | Message::UpdateRules { .. }
+            | Message::AddBot { .. }
+            | Message::RemoveBot { .. }
+            | Message::SubstituteBot { .. }
+            | Message::KickPlayer { .. }
+            | Message::TransferHost { .. }
+            | Message::UpdateSettings { .. } => true,
+            Message::Debug
+            | Message::SetReady { .. }
+            | Message::RestartGame
+            | Message::PauseGame
+            | Message::ResumeGame
+            | Message::ClaimSeat { .. }
+            | Message::Vote { .. }
+            | Message::PlayCard { .. }
+            | Message::RetractCard
+            | Message::SelectPile { .. } => false,
+        }
+    }
 }
@@ -0,0 +1,1773 @@
+use axum::extract::ws::{self, WebSocket};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::SplitSink;
+use futures::SinkExt;
+use once_cell::sync::OnceCell;
+use tokio::sync::Mutex;
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::bot::Strategy;
+use crate::bot::{self, StrategyKind, TimeoutPolicy};
+use crate::chat::{Chat, ChatError, ChatMessage, Emote};
+use crate::clock::{Reading, SavedBanks, TimeBanks};
+use crate::history::{self, GameEvent, GameLog};
+use crate::idle::{AfkThresholds, IdleTracker};
+use crate::state::{PlayerId, Presence, State, StateError, TurnId};
+use crate::vote::{Majority, Vote, VoteKind};
+
+/// The longest room code we accept
+const MAX_ROOM_CODE_LEN: usize = 32;
+
+/// All the rooms currently known to the server keyed by room code
+fn rooms() -> &'static Mutex<HashMap<String, Arc<Room>>> {
+    static ROOMS: OnceCell<Mutex<HashMap<String, Arc<Room>>>> = OnceCell::new();
+    ROOMS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Normalizes a room code so that "lunch", " Lunch " and "LUNCH" are the same room
+///
+/// Returns `None` if the code is not a valid room code
+pub fn normalize_code(code: &str) -> Option<String> {
+    let code = code.trim();
+    if code.is_empty()
+        || code.len() > MAX_ROOM_CODE_LEN
+        || !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return None;
+    }
+    Some(code.to_ascii_uppercase())
+}
+
+/// Get the room with the given code, creating it if it does not exist yet
+pub async fn get_or_create(code: &str) -> Arc<Room> {
+    rooms()
+        .lock()
+        .await
+        .entry(code.to_owned())
+        .or_insert_with(|| Arc::new(Room::new(code.to_owned())))
+        .clone()
+}
+
+/// Get the room with the given code if it exists
+pub async fn get(code: &str) -> Option<Arc<Room>> {
+    rooms().lock().await.get(code).cloned()
+}
+
+/// Forget the rooms nobody has been connected to for a while and delete their
+/// save files, checking every so often for as long as the server runs
+///
+/// Rooms with a game in progress are kept so their players can come back to it.
+pub async fn evict_idle_rooms() {
+    let idle_for = std::env::var("IDLE_ROOM_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .map_or(
+            Duration::from_secs(DEFAULT_IDLE_ROOM_SECS),
+            Duration::from_secs,
+        );
+    let mut interval = tokio::time::interval(Duration::from_secs(IDLE_ROOM_CHECK_SECS));
+    loop {
+        interval.tick().await;
+        let mut rooms = rooms().lock().await;
+        let mut idle = Vec::new();
+        for (code, room) in rooms.iter() {
+            if room.is_idle(idle_for).await {
+                idle.push(code.clone());
+            }
+        }
+        for code in idle {
+            if let Some(room) = rooms.remove(&code) {
+                room.delete_save().await;
+                println!("Removed room '{code}' after nobody used it for a while");
+            }
+        }
+    }
+}
+
+/// The directory rooms are saved in so they survive a restart
+fn saved_rooms_dir() -> PathBuf {
+    std::env::var_os("DATA_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("data"))
+        .join("rooms")
+}
+
+/// Load every saved room, returning how many were loaded
+///
+/// Players of the loaded rooms are treated as if they had just disconnected
+/// so their seats are held for them like after any other dropped connection.
+pub async fn load_saved() -> usize {
+    let dir = saved_rooms_dir();
+    let mut entries = match tokio::fs::read_dir(&dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return 0,
+        Err(e) => {
+            eprintln!("Error reading saved rooms in {}: {e}", dir.display());
+            return 0;
+        }
+    };
+
+    let mut loaded = Vec::new();
+    loop {
+        let path = match entries.next_entry().await {
+            Ok(Some(entry)) => entry.path(),
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("Error reading saved rooms in {}: {e}", dir.display());
+                break;
+            }
+        };
+        if path.extension().is_none_or(|e| e != "json") {
+            continue;
+        }
+        let code = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(normalize_code);
+        let Some(code) = code else {
+            continue;
+        };
+        match Room::load(code, &path).await {
+            Ok(room) => loaded.push(Arc::new(room)),
+            Err(e) => eprintln!("Error loading room from {}: {e}", path.display()),
+        }
+    }
+
+    rooms()
+        .lock()
+        .await
+        .extend(loaded.iter().map(|room| (room.code.clone(), room.clone())));
+    for room in &loaded {
+        room.resume().await;
+    }
+    loaded.len()
+}
+
+/// How long a seat is held for a disconnected player unless configured otherwise
+const DEFAULT_GRACE_PERIOD_SECS: u64 = 60;
+
+/// How long a room without a game in progress is kept once nobody is connected,
+/// unless configured otherwise
+const DEFAULT_IDLE_ROOM_SECS: u64 = 60 * 60;
+
+/// How often rooms are checked for having been left idle
+const IDLE_ROOM_CHECK_SECS: u64 = 60;
+
+/// How many finished games a room keeps the logs of
+const MAX_FINISHED_GAMES: usize = 20;
+
+/// How long everyone gets to look at the lobby once the game is about to start
+const COUNTDOWN_SECS: u64 = 3;
+
+/// How long players have to vote
+const VOTE_SECS: u64 = 30;
+
+/// What a room does with a seat once its player's grace period has run out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AbandonPolicy {
+    /// Keep waiting for the player to come back
+    Wait,
+    /// Make simple moves on the player's behalf until they come back
+    AutoPlay,
+}
+
+/// How far spectators are kept behind the live game
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpectatorDelay {
+    /// See everything but the last this many actions
+    Actions(usize),
+    /// See the game as it was this many seconds ago
+    Seconds(u64),
+}
+
+impl SpectatorDelay {
+    pub fn is_zero(self) -> bool {
+        matches!(
+            self,
+            SpectatorDelay::Actions(0) | SpectatorDelay::Seconds(0)
+        )
+    }
+
+    /// How many of the events in the log spectators may see right now
+    fn visible_events(self, log: &GameLog) -> usize {
+        match self {
+            SpectatorDelay::Actions(n) => log.events.len().saturating_sub(n),
+            SpectatorDelay::Seconds(s) => {
+                log.events_by(history::unix_millis().saturating_sub(s * 1000))
+            }
+        }
+    }
+}
+
+/// How long players get for each turn before a move is made for them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TurnTimer {
+    /// Seconds to play a card, zero for no limit
+    pub play_secs: u64,
+    /// Seconds to select a pile, zero for no limit
+    pub select_secs: u64,
+    /// Which card is played for a player who runs out of time
+    pub policy: TimeoutPolicy,
+}
+
+impl TurnTimer {
+    pub fn is_off(self) -> bool {
+        self.play_secs == 0 && self.select_secs == 0
+    }
+
+    /// How long players get for the turn, if it is timed at all
+    fn limit(self, turn: TurnId) -> Option<Duration> {
+        let secs = match turn.picker {
+            None => self.play_secs,
+            Some(_) => self.select_secs,
+        };
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+}
+
+/// A bank of time each player gets for a hand, like a chess clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ChessClock {
+    pub bank_secs: u64,
+    /// How moves are made for a player whose bank is empty
+    pub policy: TimeoutPolicy,
+}
+
+/// Settings that control how a room behaves (as opposed to the rules of the game)
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RoomSettings {
+    /// How long a disconnected player's seat is held before it counts as abandoned
+    pub grace_period_secs: u64,
+    pub abandon_policy: AbandonPolicy,
+    /// Keep spectators behind the live game, if set
+    pub spectator_delay: Option<SpectatorDelay>,
+    /// How many players have to agree for a vote to pass
+    #[serde(default)]
+    pub vote_majority: Majority,
+    /// Limit how long each turn may take, if set
+    #[serde(default)]
+    pub turn_timer: Option<TurnTimer>,
+    /// Give each player a bank of time for every hand, if set
+    #[serde(default)]
+    pub chess_clock: Option<ChessClock>,
+    /// Wait this many milliseconds after each step of revealing and placing
+    /// the cards of a round so players can follow along, if set
+    #[serde(default)]
+    pub reveal_step_ms: Option<u64>,
+    /// Notice players who stay connected but stop playing, if set
+    #[serde(default)]
+    pub afk: Option<AfkThresholds>,
+}
+
+impl RoomSettings {
+    fn new() -> Self {
+        let grace_period_secs = std::env::var("GRACE_PERIOD_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(DEFAULT_GRACE_PERIOD_SECS);
+        Self {
+            grace_period_secs,
+            abandon_policy: AbandonPolicy::Wait,
+            spectator_delay: None,
+            vote_majority: Majority::default(),
+            turn_timer: None,
+            chess_clock: None,
+            reveal_step_ms: None,
+            afk: None,
+        }
+    }
+}
+
+/// A room as it is written to disk
+#[derive(serde::Deserialize)]
+struct SavedRoom {
+    version: u64,
+    state: State,
+    settings: RoomSettings,
+    finished_games: Vec<GameLog>,
+    #[serde(default)]
+    chat: Chat,
+    #[serde(default)]
+    time_banks: Option<SavedBanks>,
+}
+
+/// A countdown to the start of the game
+struct Countdown {
+    started: Instant,
+    /// The host started the game whether everyone is ready or not
+    forced: bool,
+}
+
+/// How far into the log of a game everyone has been told what happened
+struct EventsSent {
+    game: u64,
+    sent: usize,
+}
+
+impl EventsSent {
+    fn caught_up(state: &State) -> Option<Self> {
+        state.log().map(|log| EventsSent {
+            game: log.id,
+            sent: log.events.len(),
+        })
+    }
+}
+
+/// The events of the game that everyone may see and hasn't been told about yet,
+/// oldest first
+fn new_public_events(sent: &mut Option<EventsSent>, log: &GameLog) -> Vec<GameEvent> {
+    let from = match sent {
+        Some(s) if s.game == log.id => s.sent,
+        // A new game is told from the start
+        _ => 0,
+    };
+    *sent = Some(EventsSent {
+        game: log.id,
+        sent: log.events.len(),
+    });
+    log.events[from..]
+        .iter()
+        .filter(|e| e.is_public())
+        .cloned()
+        .collect()
+}
+
+/// Whether the log has events everyone may see that they haven't been told
+/// about yet
+fn has_unsent_public_events(sent: &Option<EventsSent>, log: &GameLog) -> bool {
+    let from = match sent {
+        Some(s) if s.game == log.id => s.sent,
+        _ => 0,
+    };
+    log.events[from..].iter().any(|e| e.is_public())
+}
+
+/// The clock running on the current turn
+struct TurnClock {
+    turn: TurnId,
+    /// How much time was left when the clock was last started
+    remaining: Duration,
+    /// When the clock runs out, or `None` while the game is paused
+    deadline: Option<Instant>,
+    /// The deadline in milliseconds since the Unix epoch
+    expires_at: Option<u64>,
+}
+
+/// A single game room
+///
+/// Each room has its own game state and its own set of connected sockets. The
+/// state lock must always be taken before the finished games, chat, countdown,
+/// vote, turn clock, time banks and senders locks. The events sent lock is
+/// held while events are paced out so it comes before all the others.
+pub struct Room {
+    code: String,
+    /// Identifies this room to clients so they notice when a room with the same
+    /// code is not the one they joined
+    version: u64,
+    pub state: Mutex<State>,
+    pub settings: Mutex<RoomSettings>,
+    /// The logs of the games played in this room, oldest first
+    finished_games: Mutex<Vec<GameLog>>,
+    chat: Mutex<Chat>,
+    countdown: Mutex<Option<Countdown>>,
+    /// The vote the players are taking, if any
+    vote: Mutex<Option<Vote>>,
+    turn_clock: Mutex<Option<TurnClock>>,
+    /// The players' banks of time for the current hand, or the last hand of
+    /// a game that is over
+    time_banks: Mutex<Option<TimeBanks>>,
+    events_sent: Mutex<Option<EventsSent>>,
+    senders: Mutex<Senders>,
+}
+
+impl Room {
+    fn new(code: String) -> Self {
+        let version = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        Self {
+            code,
+            version,
+            state: Mutex::new(State::new()),
+            settings: Mutex::new(RoomSettings::new()),
+            finished_games: Mutex::new(Vec::new()),
+            chat: Mutex::new(Chat::default()),
+            countdown: Mutex::new(None),
+            vote: Mutex::new(None),
+            turn_clock: Mutex::new(None),
+            time_banks: Mutex::new(None),
+            events_sent: Mutex::new(None),
+            senders: Mutex::new(Senders::new()),
+        }
+    }
+
+    async fn load(code: String, path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let saved: SavedRoom = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+        // Players of a loaded game see its state, not everything that led to it
+        let events_sent = EventsSent::caught_up(&saved.state);
+        Ok(Self {
+            code,
+            version: saved.version,
+            state: Mutex::new(saved.state),
+            settings: Mutex::new(saved.settings),
+            finished_games: Mutex::new(saved.finished_games),
+            chat: Mutex::new(saved.chat),
+            countdown: Mutex::new(None),
+            vote: Mutex::new(None),
+            turn_clock: Mutex::new(None),
+            time_banks: Mutex::new(saved.time_banks.map(TimeBanks::restore)),
+            events_sent: Mutex::new(events_sent),
+            senders: Mutex::new(Senders::new()),
+        })
+    }
+
+    /// Write the room to disk
+    async fn save(&self) {
+        // Holding the state lock keeps saves of the same room from overlapping
+        let state = self.state.lock().await;
+        let settings = self.settings.lock().await.clone();
+        let finished_games = self.finished_games.lock().await;
+        let chat = self.chat.lock().await;
+        let time_banks = self
+            .time_banks
+            .lock()
+            .await
+            .as_ref()
+            .map(|b| b.save(Instant::now()));
+        let saved = serde_json::json!({
+            "version": self.version,
+            "state": &*state,
+            "settings": settings,
+            "finished_games": &*finished_games,
+            "chat": &*chat,
+            "time_banks": time_banks,
+        });
+        let dir = saved_rooms_dir();
+        let path = dir.join(format!("{}.json", self.code));
+        let tmp_path = dir.join(format!("{}.json.tmp", self.code));
+        let result = async {
+            tokio::fs::create_dir_all(&dir).await?;
+            tokio::fs::write(&tmp_path, saved.to_string()).await?;
+            tokio::fs::rename(&tmp_path, &path).await
+        };
+        if let Err(e) = result.await {
+            eprintln!("Error saving room '{}': {e}", self.code);
+        }
+    }
+
+    /// Whether nobody has been connected for `idle_for` and there is no game
+    /// in progress to come back to
+    async fn is_idle(&self, idle_for: Duration) -> bool {
+        if matches!(*self.state.lock().await, State::Game(_)) {
+            return false;
+        }
+        let senders = self.senders.lock().await;
+        senders.authenticated.is_empty()
+            && senders.spectators.is_empty()
+            && senders.last_connected.elapsed() >= idle_for
+    }
+
+    /// Remove the room's save file
+    async fn delete_save(&self) {
+        // Holding the state lock keeps this from overlapping a save
+        let _state = self.state.lock().await;
+        let path = saved_rooms_dir().join(format!("{}.json", self.code));
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("Error deleting saved room '{}': {e}", self.code),
+        }
+    }
+
+    /// Pick up where a loaded room left off
+    async fn resume(self: &Arc<Self>) {
+        let players = self.state.lock().await.human_players();
+        for player_id in players {
+            self.hold_seat(player_id).await;
+        }
+        let room = self.clone();
+        tokio::spawn(async move { room.state_changed().await });
+    }
+
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Register a player's socket, replacing any socket they had before
+    ///
+    /// Returns an id for the connection which must be passed to `disconnect`
+    pub async fn add_sender(
+        &self,
+        player_id: PlayerId,
+        sender: SplitSink<WebSocket, ws::Message>,
+    ) -> u64 {
+        let mut state = self.state.lock().await;
+        // Whoever was standing in for the player hands control back
+        if let Err(e) = state.set_stand_in(player_id, None) {
+            // Players on the waiting list have no seat to take back
+            if !matches!(e, StateError::GameNotStarted | StateError::UnknownPlayer) {
+                eprintln!("Error removing stand in for player {player_id}: {e}");
+            }
+        }
+        let mut senders = self.senders.lock().await;
+        // A host who abandoned the room while nobody else was around is replaced
+        // by whoever comes back first
+        if let Some(host) = state.host().filter(|h| senders.abandoned().contains(h)) {
+            if let Err(e) = state.set_host(player_id) {
+                eprintln!("Error passing host on from player {host}: {e}");
+            }
+        }
+        drop(state);
+        senders.next_connection_id += 1;
+        let id = senders.next_connection_id;
+        senders.disconnected.remove(&player_id);
+        senders.idle.connected(player_id, Instant::now());
+        let connection = Connection { id, sender };
+        senders.authenticated.insert(player_id, connection);
+        id
+    }
+
+    /// Remove a player's socket and hold their seat for the grace period
+    pub async fn disconnect(self: &Arc<Self>, player_id: PlayerId, connection_id: u64) {
+        {
+            let mut senders = self.senders.lock().await;
+            match senders.authenticated.get(&player_id) {
+                // The player has already reconnected on a newer socket
+                Some(c) if c.id != connection_id => return,
+                Some(_) => {}
+                None => return,
+            }
+            senders.authenticated.remove(&player_id);
+            senders.idle.disconnected(player_id);
+            senders.last_connected = Instant::now();
+        }
+        self.hold_seat(player_id).await;
+        self.broadcast_state().await;
+    }
+
+    /// Hold a player's seat for the grace period while they are not connected
+    async fn hold_seat(self: &Arc<Self>, player_id: PlayerId) {
+        let since = Instant::now();
+        self.senders.lock().await.disconnected.insert(
+            player_id,
+            Disconnected {
+                since,
+                abandoned: false,
+            },
+        );
+
+        let grace_period = Duration::from_secs(self.settings.lock().await.grace_period_secs);
+        let room = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(grace_period).await;
+            room.grace_period_expired(player_id, since).await;
+        });
+    }
+
+    async fn grace_period_expired(self: &Arc<Self>, player_id: PlayerId, since: Instant) {
+        {
+            let mut senders = self.senders.lock().await;
+            match senders.disconnected.get_mut(&player_id) {
+                Some(d) if d.since == since => d.abandoned = true,
+                // The player came back (and possibly left again) in the meantime
+                _ => return,
+            }
+        }
+        println!(
+            "Player {player_id} abandoned their seat in room '{}'",
+            self.code
+        );
+        self.pass_on_host(player_id).await;
+        self.state_changed().await;
+    }
+
+    /// Give the host role to a connected player if the host abandoned the room
+    async fn pass_on_host(&self, abandoned: PlayerId) {
+        let mut state = self.state.lock().await;
+        if state.host() != Some(abandoned) {
+            return;
+        }
+        let senders = self.senders.lock().await;
+        let next = state
+            .human_players()
+            .into_iter()
+            .find(|id| senders.authenticated.contains_key(id));
+        if let Some(next) = next {
+            match state.set_host(next) {
+                Ok(()) => println!("Player {next} is now the host of room '{}'", self.code),
+                Err(e) => eprintln!("Error passing host on to player {next}: {e}"),
+            }
+        }
+    }
+
+    /// Close a player's socket, for example because they were kicked
+    pub async fn close_connection(&self, player_id: PlayerId, reason: &'static str) {
+        let mut senders = self.senders.lock().await;
+        senders.disconnected.remove(&player_id);
+        senders.idle.disconnected(player_id);
+        let Some(mut connection) = senders.authenticated.remove(&player_id) else {
+            return;
+        };
+        senders.last_connected = Instant::now();
+        let close = ws::Message::Close(Some(ws::CloseFrame {
+            code: ws::close_code::POLICY,
+            reason: reason.into(),
+        }));
+        if let Err(e) = connection.sender.send(close).await {
+            eprintln!("Error closing socket: {e}");
+        }
+    }
+
+    /// Whether the player's grace period ran out without them coming back
+    pub async fn is_abandoned(&self, player_id: PlayerId) -> bool {
+        self.senders.lock().await.abandoned().contains(&player_id)
+    }
+
+    /// Note that a player did something, handing control back to them if a
+    /// bot took over while they were away
+    ///
+    /// Returns whether the player had been away.
+    pub async fn touch(&self, player_id: PlayerId) -> bool {
+        self.senders
+            .lock()
+            .await
+            .idle
+            .touch(player_id, Instant::now())
+    }
+
+    /// Catch up with a change to the state and let everyone know about it
+    pub async fn state_changed(self: &Arc<Self>) {
+        self.resolve_vote().await;
+        // Nobody's time runs while everyone is shown how the last round played out
+        self.stop_time_banks().await;
+        loop {
+            self.run_automation().await;
+            self.check_ready().await;
+            self.archive_finished_game().await;
+            self.save().await;
+            self.send_game_events().await;
+            // Moving for players who were idle too long can leave others owing a move
+            if !self.update_idle().await {
+                break;
+            }
+        }
+        self.update_turn_clock().await;
+        self.update_time_banks().await;
+        self.broadcast_state().await;
+
+        // Spectators who are kept a while behind see this change once the delay is over
+        let delay = self.settings.lock().await.spectator_delay;
+        if let Some(SpectatorDelay::Seconds(s)) = delay {
+            let room = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(s)).await;
+                room.broadcast_to_spectators().await;
+            });
+        }
+    }
+
+    /// Count down to the game once everyone is ready, or stop counting if
+    /// somebody no longer is
+    async fn check_ready(self: &Arc<Self>) {
+        let ready = {
+            let state = self.state.lock().await;
+            let present = self
+                .senders
+                .lock()
+                .await
+                .authenticated
+                .keys()
+                .copied()
+                .collect();
+            state.everyone_ready(&present)
+        };
+        if ready {
+            self.start_countdown(false).await;
+            return;
+        }
+        let mut countdown = self.countdown.lock().await;
+        if countdown.as_ref().is_some_and(|c| !c.forced) {
+            *countdown = None;
+            drop(countdown);
+            let msg = serde_json::json!({ "event": "countdown_cancelled" });
+            self.broadcast(msg.to_string()).await;
+        }
+    }
+
+    /// Start the game after a short countdown
+    ///
+    /// If `forced` is set the game starts even if not everyone is ready by then.
+    pub async fn start_countdown(self: &Arc<Self>, forced: bool) {
+        let started = Instant::now();
+        {
+            let mut countdown = self.countdown.lock().await;
+            if let Some(c) = countdown.as_mut() {
+                c.forced |= forced;
+                return;
+            }
+            *countdown = Some(Countdown { started, forced });
+        }
+        let msg = serde_json::json!({
+            "event": "countdown",
+            "seconds": COUNTDOWN_SECS,
+        });
+        self.broadcast(msg.to_string()).await;
+
+        tokio::spawn(self.clone().countdown_over(started));
+    }
+
+    /// Start the game once the countdown started at `started` is over
+    ///
+    /// This is boxed because starting the game leads back to `start_countdown`.
+    fn countdown_over(self: Arc<Self>, started: Instant) -> BoxFuture<'static, ()> {
+        async move {
+            tokio::time::sleep(Duration::from_secs(COUNTDOWN_SECS)).await;
+            {
+                let mut countdown = self.countdown.lock().await;
+                match countdown.as_ref() {
+                    Some(c) if c.started == started => *countdown = None,
+                    // The countdown was cancelled in the meantime
+                    _ => return,
+                }
+            }
+            if let Err(e) = self.state.lock().await.start_game() {
+                eprintln!("Error starting game in room '{}': {e}", self.code);
+                let msg = serde_json::json!({ "event": "countdown_cancelled" });
+                self.broadcast(msg.to_string()).await;
+                return;
+            }
+            self.state_changed().await;
+        }
+        .boxed()
+    }
+
+    /// Ask every connected player to vote, counting the player who asked as voting yes
+    pub async fn start_vote(
+        self: &Arc<Self>,
+        state: &State,
+        kind: VoteKind,
+        started_by: PlayerId,
+    ) -> Result<(), StateError> {
+        let majority = self.settings.lock().await.vote_majority;
+        let mut vote = self.vote.lock().await;
+        if vote.is_some() {
+            return Err(StateError::VoteInProgress);
+        }
+        let voters = {
+            let senders = self.senders.lock().await;
+            state
+                .human_players()
+                .into_iter()
+                .filter(|id| *id == started_by || senders.authenticated.contains_key(id))
+                .collect()
+        };
+        let expires_at = history::unix_millis() + VOTE_SECS * 1000;
+        let new_vote = Vote::new(kind, started_by, voters, majority, expires_at);
+        let started = new_vote.started;
+        *vote = Some(new_vote);
+
+        let room = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(VOTE_SECS)).await;
+            room.vote_expired(started).await;
+        });
+        Ok(())
+    }
+
+    pub async fn cast_vote(&self, player_id: PlayerId, yes: bool) -> Result<(), StateError> {
+        self.vote
+            .lock()
+            .await
+            .as_mut()
+            .ok_or(StateError::NoVote)?
+            .cast(player_id, yes)
+    }
+
+    /// Carry out the vote in progress once it is decided
+    async fn resolve_vote(&self) {
+        let mut state = self.state.lock().await;
+        let mut vote = self.vote.lock().await;
+        let Some(v) = vote.as_ref() else {
+            return;
+        };
+        // A vote about the game in progress fails once the game is over
+        let in_game = matches!(*state, State::Game(_));
+        let moot = match v.kind {
+            VoteKind::Restart => !in_game,
+            // The seat is no longer free if its player came back
+            VoteKind::TakeSeat { seat, .. } => {
+                !in_game || !self.senders.lock().await.abandoned().contains(&seat)
+            }
+            VoteKind::Pause => state.can_pause(true).is_err(),
+            VoteKind::Resume => state.can_pause(false).is_err(),
+        };
+        let outcome = if moot { Some(false) } else { v.outcome() };
+        let Some(passed) = outcome else {
+            return;
+        };
+        let kind = v.kind;
+        *vote = None;
+        if passed {
+            match kind {
+                VoteKind::Restart => state.restart(),
+                VoteKind::TakeSeat { seat, player_id } => match state.take_seat(seat, player_id) {
+                    Ok(()) => self.senders.lock().await.take_seat(seat, player_id),
+                    Err(e) => eprintln!("Error giving seat {seat} to player {player_id}: {e}"),
+                },
+                VoteKind::Pause | VoteKind::Resume => {
+                    if let Err(e) = state.set_paused(kind == VoteKind::Pause) {
+                        eprintln!("Error pausing or resuming room '{}': {e}", self.code);
+                    }
+                }
+            }
+        }
+        drop(vote);
+        drop(state);
+        self.broadcast(vote_result(kind, passed)).await;
+    }
+
+    async fn vote_expired(&self, started: Instant) {
+        let kind = {
+            let mut vote = self.vote.lock().await;
+            match vote.as_ref() {
+                Some(v) if v.started == started => {
+                    let kind = v.kind;
+                    *vote = None;
+                    kind
+                }
+                // The vote was decided in the meantime
+                _ => return,
+            }
+        };
+        self.broadcast(vote_result(kind, false)).await;
+        self.broadcast_state().await;
+    }
+
+    /// Start the clock when a new turn begins, or stop it if the room no longer
+    /// has a turn to time
+    async fn update_turn_clock(self: &Arc<Self>) {
+        let timer = self.settings.lock().await.turn_timer;
+        let state = self.state.lock().await;
+        let mut clock = self.turn_clock.lock().await;
+        let Some((turn, limit)) = state
+            .turn_id()
+            .and_then(|turn| Some((turn, timer?.limit(turn)?)))
+        else {
+            *clock = None;
+            return;
+        };
+        let clock = match clock.as_mut() {
+            Some(c) if c.turn == turn => c,
+            _ => clock.insert(TurnClock {
+                turn,
+                remaining: limit,
+                deadline: None,
+                expires_at: None,
+            }),
+        };
+        // The clock stands still while the game is paused
+        match (state.is_paused(), clock.deadline) {
+            (true, Some(deadline)) => {
+                clock.remaining = deadline.saturating_duration_since(Instant::now());
+                clock.deadline = None;
+                clock.expires_at = None;
+            }
+            (false, None) => {
+                let deadline = Instant::now() + clock.remaining;
+                clock.deadline = Some(deadline);
+                clock.expires_at =
+                    Some(history::unix_millis() + clock.remaining.as_millis() as u64);
+                tokio::spawn(self.clone().turn_timed_out(deadline));
+            }
+            _ => {}
+        }
+    }
+
+    /// Move for every player who still owes a move once the clock that runs
+    /// out at `deadline` does
+    ///
+    /// This is boxed because the moves lead back to `update_turn_clock`.
+    fn turn_timed_out(self: Arc<Self>, deadline: Instant) -> BoxFuture<'static, ()> {
+        async move {
+            tokio::time::sleep_until(deadline.into()).await;
+            let Some(mut policy) = self.settings.lock().await.turn_timer.map(|t| t.policy) else {
+                return;
+            };
+            {
+                let mut state = self.state.lock().await;
+                let turn = {
+                    let clock = self.turn_clock.lock().await;
+                    match clock.as_ref() {
+                        Some(c) if c.deadline == Some(deadline) => c.turn,
+                        // Everyone moved in time or the clock was stopped
+                        _ => return,
+                    }
+                };
+                for player_id in state.pending_players() {
+                    // The last move of a round ends the turn for the other players too
+                    if state.turn_id() != Some(turn) {
+                        break;
+                    }
+                    let Some(view) = state.view_for(player_id) else {
+                        continue;
+                    };
+                    println!("Player {player_id} ran out of time in room '{}'", self.code);
+                    if let Err(e) = state.apply_move(player_id, bot::decide(&mut policy, &view)) {
+                        eprintln!("Error moving for player {player_id} who ran out of time: {e}");
+                    }
+                }
+            }
+            self.state_changed().await;
+        }
+        .boxed()
+    }
+
+    /// Run down the banks of the players who owe a move
+    async fn update_time_banks(self: &Arc<Self>) {
+        let chess_clock = self.settings.lock().await.chess_clock;
+        let state = self.state.lock().await;
+        let mut banks = self.time_banks.lock().await;
+        let now = Instant::now();
+        let Some(turn) = state.turn_id() else {
+            if matches!(*state, State::GameOver(..)) {
+                // The final readings are kept for the game over summary
+                if let Some(banks) = banks.as_mut() {
+                    banks.update(&[], now);
+                }
+            } else {
+                *banks = None;
+            }
+            return;
+        };
+        let Some(chess_clock) = chess_clock else {
+            *banks = None;
+            return;
+        };
+        let banks = match banks.as_mut() {
+            Some(b) if b.is_for_hand_of(turn) => b,
+            _ => banks.insert(TimeBanks::new(
+                turn,
+                state.human_players(),
+                Duration::from_secs(chess_clock.bank_secs),
+            )),
+        };
+        if let Some(expiry) = banks.update(&state.pending_players(), now) {
+            tokio::spawn(self.clone().time_bank_expired(expiry));
+        }
+    }
+
+    /// Stop every bank until the players are asked to move again
+    async fn stop_time_banks(&self) {
+        if let Some(banks) = self.time_banks.lock().await.as_mut() {
+            banks.update(&[], Instant::now());
+        }
+    }
+
+    /// Move for a player whose bank runs out at `expiry` if they still owe a move
+    ///
+    /// This is boxed because the moves lead back to `update_time_banks`.
+    fn time_bank_expired(self: Arc<Self>, expiry: Instant) -> BoxFuture<'static, ()> {
+        async move {
+            tokio::time::sleep_until(expiry.into()).await;
+            let out_of_time = {
+                let state = self.state.lock().await;
+                let banks = self.time_banks.lock().await;
+                let now = Instant::now();
+                banks.as_ref().is_some_and(|banks| {
+                    state
+                        .pending_players()
+                        .into_iter()
+                        .any(|id| banks.is_empty(id, now))
+                })
+            };
+            if out_of_time {
+                self.state_changed().await;
+            }
+        }
+        .boxed()
+    }
+
+    /// Notice which players have owed a move for too long without doing anything
+    ///
+    /// Returns whether a bot has to take over for anyone.
+    async fn update_idle(self: &Arc<Self>) -> bool {
+        let afk = self.settings.lock().await.afk;
+        let state = self.state.lock().await;
+        let mut senders = self.senders.lock().await;
+        let Some(afk) = afk else {
+            senders.idle.clear();
+            return false;
+        };
+        // Players start afresh once a paused game is resumed
+        let turn = state.turn_id().filter(|_| !state.is_paused());
+        let (taken_over, next_check) =
+            senders
+                .idle
+                .update(turn, &state.pending_players(), afk, Instant::now());
+        if let Some(at) = next_check {
+            tokio::spawn(self.clone().idle_check(at));
+        }
+        taken_over
+    }
+
+    /// Catch up with players who became idle at `at`
+    ///
+    /// This is boxed because it leads back to `update_idle`.
+    fn idle_check(self: Arc<Self>, at: Instant) -> BoxFuture<'static, ()> {
+        async move {
+            tokio::time::sleep_until(at.into()).await;
+            if self.senders.lock().await.idle.next_check() != Some(at) {
+                // Somebody moved in the meantime
+                return;
+            }
+            self.state_changed().await;
+        }
+        .boxed()
+    }
+
+    /// Tell everyone how the cards played were revealed and placed, one step at
+    /// a time, before they are sent the state the steps led to
+    async fn send_game_events(&self) {
+        let mut sent = self.events_sent.lock().await;
+        let (step, spectator_delay) = {
+            let settings = self.settings.lock().await;
+            (
+                settings.reveal_step_ms.map(Duration::from_millis),
+                settings.spectator_delay,
+            )
+        };
+        let events = match self.state.lock().await.log() {
+            Some(log) => new_public_events(&mut sent, log),
+            None => return,
+        };
+        for event in events {
+            // Spectators who are kept behind would see the steps too early
+            let msg = serde_json::to_string(&event).unwrap();
+            self.broadcast_to(msg, spectator_delay.is_none()).await;
+            if let Some(step) = step {
+                tokio::time::sleep(step).await;
+            }
+        }
+    }
+
+    /// Keep the log of a game that just finished
+    async fn archive_finished_game(&self) {
+        let state = self.state.lock().await;
+        let Some(log) = state.finished_log() else {
+            return;
+        };
+        let mut finished_games = self.finished_games.lock().await;
+        if finished_games.last().map(|l| l.id) != Some(log.id) {
+            finished_games.push(log.clone());
+            if finished_games.len() > MAX_FINISHED_GAMES {
+                finished_games.remove(0);
+            }
+        }
+    }
+
+    /// The logs of the games finished in this room, oldest first
+    pub async fn finished_games(&self) -> Vec<GameLog> {
+        self.finished_games.lock().await.clone()
+    }
+
+    /// Make moves for every computer controlled seat that owes one
+    ///
+    /// This covers bots, stand ins, players whose bank of time is empty,
+    /// players who were away too long and, if the room auto plays, abandoned
+    /// seats. Strategies think without
+    /// holding the state lock so slow bots don't block the room. Returns
+    /// whether any move was made
+    pub async fn run_automation(&self) -> bool {
+        let (auto_play_abandoned, out_of_time_policy) = {
+            let settings = self.settings.lock().await;
+            (
+                settings.abandon_policy == AbandonPolicy::AutoPlay,
+                settings.chess_clock.map(|c| c.policy),
+            )
+        };
+        let mut changed = false;
+        loop {
+            let next = {
+                let state = self.state.lock().await;
+                let banks = self.time_banks.lock().await;
+                let (abandoned, taken_over) = {
+                    let senders = self.senders.lock().await;
+                    (senders.abandoned(), senders.idle.taken_over().clone())
+                };
+                let now = Instant::now();
+                state.pending_players().into_iter().find_map(|player_id| {
+                    let out_of_time = out_of_time_policy
+                        .filter(|_| banks.as_ref().is_some_and(|b| b.is_empty(player_id, now)));
+                    let strategy: Box<dyn Strategy> =
+                        if let Some(kind) = state.controller(player_id) {
+                            kind.strategy()
+                        } else if let Some(policy) = out_of_time {
+                            Box::new(policy)
+                        } else if taken_over.contains(&player_id)
+                            || (auto_play_abandoned && abandoned.contains(&player_id))
+                        {
+                            StrategyKind::LowestRisk.strategy()
+                        } else {
+                            return None;
+                        };
+                    Some((player_id, strategy, state.view_for(player_id)?))
+                })
+            };
+            let Some((player_id, mut strategy, view)) = next else {
+                break;
+            };
+
+            let mv = {
+                let view = view.clone();
+                tokio::task::spawn_blocking(move || bot::decide(strategy.as_mut(), &view)).await
+            };
+            let mv = match mv {
+                Ok(mv) => mv,
+                Err(e) => {
+                    eprintln!("Error deciding move for player {player_id}: {e}");
+                    break;
+                }
+            };
+
+            let mut state = self.state.lock().await;
+            // Somebody else moved for this player while the strategy was thinking
+            if state.view_for(player_id).as_ref() != Some(&view) {
+                continue;
+            }
+            if let Err(e) = state.apply_move(player_id, mv) {
+                eprintln!("Error auto playing for player {player_id}: {e}");
+                break;
+            }
+            changed = true;
+        }
+        changed
+    }
+
+    pub async fn send_message(&self, player_id: PlayerId, msg: String) {
+        let mut senders = self.senders.lock().await;
+        if let Some(connection) = senders.authenticated.get_mut(&player_id) {
+            if let Err(e) = connection.sender.send(ws::Message::Text(msg)).await {
+                eprintln!("Error sending message: {e}");
+            }
+        }
+    }
+
+    /// Register a socket that watches the room without playing
+    ///
+    /// Returns an id for the connection which must be passed to `remove_spectator`
+    pub async fn add_spectator(
+        &self,
+        name: String,
+        sender: SplitSink<WebSocket, ws::Message>,
+    ) -> u64 {
+        let mut senders = self.senders.lock().await;
+        senders.next_connection_id += 1;
+        let id = senders.next_connection_id;
+        senders.spectators.insert(id, Spectator { name, sender });
+        id
+    }
+
+    pub async fn remove_spectator(&self, connection_id: u64) {
+        {
+            let mut senders = self.senders.lock().await;
+            senders.spectators.remove(&connection_id);
+            senders.last_connected = Instant::now();
+        }
+        self.broadcast_state().await;
+    }
+
+    pub async fn send_to_spectator(&self, connection_id: u64, msg: String) {
+        let mut senders = self.senders.lock().await;
+        if let Some(spectator) = senders.spectators.get_mut(&connection_id) {
+            if let Err(e) = spectator.sender.send(ws::Message::Text(msg)).await {
+                eprintln!("Error sending message: {e}");
+            }
+        }
+    }
+
+    /// Send a chat message from a player to everyone in the room
+    ///
+    /// Spectators who are kept behind only see the chat when they start
+    /// watching, as players talking about the game could give it away.
+    pub async fn post_chat(&self, player_id: PlayerId, text: &str) -> Result<(), ChatError> {
+        let message = {
+            let state = self.state.lock().await;
+            let name = state
+                .get_player(player_id)
+                .ok_or(ChatError::UnknownPlayer)?
+                .name()
+                .to_owned();
+            self.chat
+                .lock()
+                .await
+                .post(player_id, name, text, Instant::now())?
+        };
+        // The chat is written to disk with the room the next time the game moves on
+        let spectator_delay = self.settings.lock().await.spectator_delay;
+        let msg = serde_json::json!({
+            "event": "chat",
+            "message": message,
+        });
+        self.broadcast_to(msg.to_string(), spectator_delay.is_none())
+            .await;
+        Ok(())
+    }
+
+    /// Send a reaction from a player to everyone connected to the room
+    ///
+    /// If `at_pile_taker` is set the reaction is aimed at whoever last took a pile.
+    /// Spectators who are kept behind don't get reactions, which could give away
+    /// what they haven't seen yet.
+    pub async fn react(
+        &self,
+        player_id: PlayerId,
+        emote: Emote,
+        at_pile_taker: bool,
+    ) -> Result<(), ChatError> {
+        let reaction = {
+            let state = self.state.lock().await;
+            let name = state
+                .get_player(player_id)
+                .ok_or(ChatError::UnknownPlayer)?
+                .name()
+                .to_owned();
+            let target = if at_pile_taker {
+                Some(pile_taker(state.log())?)
+            } else {
+                None
+            };
+            self.chat
+                .lock()
+                .await
+                .react(player_id, name, emote, target, Instant::now())?
+        };
+        let spectator_delay = self.settings.lock().await.spectator_delay;
+        let mut msg = serde_json::json!(reaction);
+        msg["event"] = "reaction".into();
+        self.broadcast_to(msg.to_string(), spectator_delay.is_none())
+            .await;
+        Ok(())
+    }
+
+    /// Send the same message to every player and spectator in the room
+    async fn broadcast(&self, msg: String) {
+        self.broadcast_to(msg, true).await;
+    }
+
+    /// Send the same message to every player, and to the spectators if
+    /// `spectators` is set
+    async fn broadcast_to(&self, msg: String, spectators: bool) {
+        let mut senders = self.senders.lock().await;
+        let Senders {
+            authenticated,
+            spectators: watchers,
+            ..
+        } = &mut *senders;
+        let sinks = authenticated.values_mut().map(|c| &mut c.sender).chain(
+            watchers
+                .values_mut()
+                .filter(|_| spectators)
+                .map(|s| &mut s.sender),
+        );
+        for sender in sinks {
+            if let Err(e) = sender.send(ws::Message::Text(msg.clone())).await {
+                eprintln!("Error sending broadcast: {e}");
+            }
+        }
+    }
+
+    /// Send the current state to everyone connected to this room
+    pub async fn broadcast_state(&self) {
+        self.send_state_to_recipients(Recipients::Everyone).await;
+    }
+
+    /// Send the current state to everyone watching this room
+    async fn broadcast_to_spectators(&self) {
+        self.send_state_to_recipients(Recipients::Spectators).await;
+    }
+
+    /// Send the current state and the chat so far to a single user in this room
+    pub async fn send_state(&self, player_id: PlayerId) {
+        self.send_state_to_recipients(Recipients::Player(player_id))
+            .await;
+    }
+
+    /// Send the current state and the chat so far to a spectator who just
+    /// started watching, and let everyone else know they are watching
+    pub async fn welcome_spectator(&self, connection_id: u64) {
+        self.send_state_to_recipients(Recipients::Spectator(connection_id))
+            .await;
+        self.send_state_to_recipients(Recipients::EveryoneBut(connection_id))
+            .await;
+    }
+
+    /// Send each recipient their view of the game along with everything about
+    /// the room itself
+    ///
+    /// Only someone who just connected is sent the chat history, everyone else
+    /// keeps up with it through chat events.
+    async fn send_state_to_recipients(&self, recipients: Recipients) {
+        // Waiting for the steps of a reveal to be paced out keeps the state they
+        // lead to from giving them away
+        let sent = self.events_sent.lock().await;
+        let settings = self.settings.lock().await.clone();
+        let state = self.state.lock().await;
+        let broadcast = matches!(
+            recipients,
+            Recipients::Everyone | Recipients::EveryoneBut(_) | Recipients::Spectators
+        );
+        if broadcast
+            && state
+                .log()
+                .is_some_and(|log| has_unsent_public_events(&sent, log))
+        {
+            // The room is about to pace the steps out and send the state after them
+            return;
+        }
+        drop(sent);
+        let chat = match recipients {
+            Recipients::Player(_) | Recipients::Spectator(_) => {
+                Some(self.chat.lock().await.messages().clone())
+            }
+            Recipients::Everyone | Recipients::EveryoneBut(_) | Recipients::Spectators => None,
+        };
+        let vote = self.vote.lock().await.clone();
+        let turn_clock = self.turn_clock.lock().await;
+        let time_banks = self
+            .time_banks
+            .lock()
+            .await
+            .as_ref()
+            .map(|b| b.readings(Instant::now()));
+        let mut senders = self.senders.lock().await;
+        let presence = senders.presence();
+        let room = room_info(
+            &settings,
+            chat.as_ref(),
+            vote.as_ref(),
+            turn_clock.as_ref(),
+            time_banks,
+            &senders,
+        );
+
+        for (player_id, connection) in senders.authenticated.iter_mut() {
+            let wanted = match recipients {
+                Recipients::Everyone | Recipients::EveryoneBut(_) => true,
+                Recipients::Spectators | Recipients::Spectator(_) => false,
+                Recipients::Player(id) => id == *player_id,
+            };
+            if wanted {
+                send_state_to(
+                    state.serialize_for_user(*player_id, &presence),
+                    &room,
+                    &mut connection.sender,
+                )
+                .await;
+            }
+        }
+
+        if matches!(recipients, Recipients::Player(_)) {
+            return;
+        }
+        let watched = spectator_view(&state, &settings, &presence);
+        let mut watched_room = room;
+        if watched.get("delayed").is_some() {
+            // These would give away the timing of the turn the delay hides
+            for key in ["vote", "turn_clock", "time_banks"] {
+                watched_room[key] = serde_json::Value::Null;
+            }
+        }
+        for (id, spectator) in senders.spectators.iter_mut() {
+            let wanted = match recipients {
+                Recipients::Spectator(wanted) => wanted == *id,
+                Recipients::EveryoneBut(unwanted) => unwanted != *id,
+                Recipients::Everyone | Recipients::Spectators | Recipients::Player(_) => true,
+            };
+            if !wanted {
+                continue;
+            }
+            send_state_to(watched.clone(), &watched_room, &mut spectator.sender).await;
+        }
+    }
+}
+
+/// Who to send the state to
+#[derive(Clone, Copy)]
+enum Recipients {
+    /// Every player and spectator
+    Everyone,
+    /// Every player and spectator but the spectator with this connection id
+    EveryoneBut(u64),
+    Spectators,
+    Player(PlayerId),
+    /// The spectator with this connection id
+    Spectator(u64),
+}
+
+struct Senders {
+    authenticated: HashMap<PlayerId, Connection>,
+    /// Sockets watching the room keyed by connection id
+    spectators: BTreeMap<u64, Spectator>,
+    disconnected: HashMap<PlayerId, Disconnected>,
+    next_connection_id: u64,
+    /// When somebody last left, or when the room was created or loaded if
+    /// nobody has left yet
+    last_connected: Instant,
+    /// Which connected players are away from the keyboard
+    idle: IdleTracker,
+}
+
+/// A player's open socket
+struct Connection {
+    id: u64,
+    sender: SplitSink<WebSocket, ws::Message>,
+}
+
+/// Somebody watching the room without playing
+struct Spectator {
+    name: String,
+    sender: SplitSink<WebSocket, ws::Message>,
+}
+
+/// A player whose socket dropped
+struct Disconnected {
+    since: Instant,
+    /// Whether the grace period ran out before the player came back
+    abandoned: bool,
+}
+
+impl Senders {
+    fn new() -> Self {
+        Self {
+            authenticated: HashMap::new(),
+            spectators: BTreeMap::new(),
+            disconnected: HashMap::new(),
+            next_connection_id: 0,
+            last_connected: Instant::now(),
+            idle: IdleTracker::default(),
+        }
+    }
+
+    fn presence(&self) -> HashMap<PlayerId, Presence> {
+        let online = self.authenticated.keys().map(|id| {
+            let presence = if self.idle.is_away(*id) {
+                Presence::Away
+            } else {
+                Presence::Online
+            };
+            (*id, presence)
+        });
+        let disconnected = self.disconnected.iter().map(|(id, d)| {
+            let presence = if d.abandoned {
+                Presence::Offline
+            } else {
+                Presence::Reconnecting
+            };
+            (*id, presence)
+        });
+        online.chain(disconnected).collect()
+    }
+
+    /// Move a waiting player's socket over to the seat they took
+    fn take_seat(&mut self, seat: PlayerId, player_id: PlayerId) {
+        self.disconnected.remove(&seat);
+        if let Some(connection) = self.authenticated.remove(&player_id) {
+            self.authenticated.insert(seat, connection);
+            self.idle.moved(player_id, seat);
+        }
+    }
+
+    /// The names of the spectators in the order they arrived
+    fn spectator_names(&self) -> Vec<String> {
+        self.spectators.values().map(|s| s.name.clone()).collect()
+    }
+
+    fn abandoned(&self) -> HashSet<PlayerId> {
+        self.disconnected
+            .iter()
+            .filter(|(_, d)| d.abandoned)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+}
+
+/// What spectators get to see of the state, taking any delay into account
+///
+/// Nothing more happens once a game is over, so the delay is lifted then.
+fn spectator_view(
+    state: &State,
+    settings: &RoomSettings,
+    presence: &HashMap<PlayerId, Presence>,
+) -> serde_json::Value {
+    match (settings.spectator_delay, state.log()) {
+        (Some(delay), Some(log)) if state.finished_log().is_none() => {
+            state.serialize_for_delayed_spectator(delay.visible_events(log), presence)
+        }
+        _ => state.serialize_for_spectator(presence),
+    }
+}
+
+/// Who a reaction aimed at the last player to take a pile is for
+fn pile_taker(log: Option<&GameLog>) -> Result<PlayerId, ChatError> {
+    log.and_then(|log| log.last_pile_taker())
+        .ok_or(ChatError::NoPileTaken)
+}
+
+/// The message telling everyone how a vote ended
+fn vote_result(kind: VoteKind, passed: bool) -> String {
+    serde_json::json!({
+        "event": "vote_result",
+        "kind": kind,
+        "passed": passed,
+    })
+    .to_string()
+}
+
+/// Everything about the room itself, as opposed to the game, that clients are sent
+fn room_info(
+    settings: &RoomSettings,
+    chat: Option<&VecDeque<ChatMessage>>,
+    vote: Option<&Vote>,
+    turn_clock: Option<&TurnClock>,
+    time_banks: Option<BTreeMap<PlayerId, Reading>>,
+    senders: &Senders,
+) -> serde_json::Value {
+    let turn_clock = turn_clock.map(|c| {
+        let remaining = c
+            .deadline
+            .map_or(c.remaining, |d| d.saturating_duration_since(Instant::now()));
+        serde_json::json!({
+            "expires_at": c.expires_at,
+            "remaining_ms": remaining.as_millis() as u64,
+        })
+    });
+    let mut info = serde_json::json!({
+        "settings": settings,
+        "vote": vote,
+        "turn_clock": turn_clock,
+        "time_banks": time_banks,
+        "spectators": senders.spectator_names(),
+    });
+    if let Some(chat) = chat {
+        info["chat"] = serde_json::json!(chat);
+    }
+    info
+}
+
+/// Send a view of the game along with everything about the room itself
+async fn send_state_to(
+    mut response: serde_json::Value,
+    room: &serde_json::Value,
+    sender: &mut SplitSink<WebSocket, ws::Message>,
+) {
+    for (key, value) in room.as_object().into_iter().flatten() {
+        response[key] = value.clone();
+    }
+    if let Err(e) = sender
+        .send(ws::Message::Text(serde_json::to_string(&response).unwrap()))
+        .await
+    {
+        eprintln!("Error sending broadcast: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_code() {
+        assert_eq!(normalize_code(" lunch-1 ").as_deref(), Some("LUNCH-1"));
+        assert_eq!(normalize_code(""), None);
+        assert_eq!(normalize_code("a b"), None);
+        assert_eq!(normalize_code(&"a".repeat(33)), None);
+    }
+
+    #[test]
+    fn test_spectator_delay() {
+        let mut log = GameLog::new(Default::default(), Default::default());
+        for _ in 0..5 {
+            log.push(crate::history::GameEvent::Deal {
+                hands: Vec::new(),
+                piles: Vec::new(),
+            });
+        }
+        assert_eq!(SpectatorDelay::Actions(2).visible_events(&log), 3);
+        assert_eq!(SpectatorDelay::Actions(10).visible_events(&log), 0);
+        assert_eq!(SpectatorDelay::Seconds(60).visible_events(&log), 0);
+        assert_eq!(SpectatorDelay::Actions(0).visible_events(&log), 5);
+        assert!(SpectatorDelay::Seconds(0).is_zero());
+    }
+
+    #[test]
+    fn test_spectator_delay_ends_with_the_game() {
+        let mut state = State::new();
+        state.join("Bill".to_owned()).unwrap();
+        state.add_bot(StrategyKind::Random).unwrap();
+        state.start_game().unwrap();
+        let mut settings = RoomSettings::new();
+        settings.spectator_delay = Some(SpectatorDelay::Actions(3));
+        let presence = HashMap::new();
+        assert_eq!(
+            spectator_view(&state, &settings, &presence)["delayed"],
+            true
+        );
+
+        while let Some(player_id) = state.pending_players().first().copied() {
+            let view = state.view_for(player_id).unwrap();
+            let mv = bot::decide(StrategyKind::LowestRisk.strategy().as_mut(), &view);
+            state.apply_move(player_id, mv).unwrap();
+        }
+        let watched = spectator_view(&state, &settings, &presence);
+        assert_eq!(watched, state.serialize_for_spectator(&presence));
+        assert_eq!(watched["state"], "game_over");
+    }
+
+    #[tokio::test]
+    async fn test_idle_rooms() {
+        let room = Room::new("IDLE".to_owned());
+        assert!(room.is_idle(Duration::ZERO).await);
+        assert!(!room.is_idle(Duration::from_secs(60)).await);
+
+        {
+            let mut state = room.state.lock().await;
+            state.join("Bill".to_owned()).unwrap();
+            state.join("Ted".to_owned()).unwrap();
+            state.start_game().unwrap();
+        }
+        // Players may come back to a game in progress however long it takes
+        assert!(!room.is_idle(Duration::ZERO).await);
+    }
+
+    #[test]
+    fn test_new_public_events() {
+        let ted = PlayerId::from(0);
+        let mut log = GameLog::new(Default::default(), Default::default());
+        log.push(GameEvent::Deal {
+            hands: vec![(ted, vec![5])],
+            piles: vec![vec![1]],
+        });
+        log.push(GameEvent::CardPlayed {
+            player_id: ted,
+            card: 5,
+        });
+        let mut sent = None;
+        assert_eq!(new_public_events(&mut sent, &log), Vec::new());
+
+        let reveal = GameEvent::Reveal {
+            round: 1,
+            cards: vec![(ted, 5)],
+        };
+        let placed = GameEvent::Placed {
+            player_id: ted,
+            card: 5,
+            pile: 0,
+            taken: Vec::new(),
+            points: 0,
+        };
+        log.push(reveal.clone());
+        log.push(placed.clone());
+        assert_eq!(new_public_events(&mut sent, &log), vec![reveal, placed]);
+        assert_eq!(new_public_events(&mut sent, &log), Vec::new());
+
+        // The next game starts from the beginning of its own log
+        let mut next = GameLog::new(Default::default(), Default::default());
+        let over = GameEvent::HandOver {
+            hand: 1,
+            points: Vec::new(),
+        };
+        next.push(over.clone());
+        assert_eq!(new_public_events(&mut sent, &next), vec![over]);
+    }
+
+    #[test]
+    fn test_pile_taker() {
+        let ted = PlayerId::from(0);
+        let bill = PlayerId::from(1);
+        let deal = GameEvent::Deal {
+            hands: vec![(ted, vec![5, 60]), (bill, vec![7, 61])],
+            piles: vec![vec![1], vec![2], vec![3], vec![4]],
+        };
+        let mut log = GameLog::new(Default::default(), Default::default());
+        assert!(matches!(pile_taker(None), Err(ChatError::NoPileTaken)));
+        log.push(deal.clone());
+        log.push(GameEvent::Placed {
+            player_id: ted,
+            card: 5,
+            pile: 3,
+            taken: Vec::new(),
+            points: 0,
+        });
+        assert!(matches!(
+            pile_taker(Some(&log)),
+            Err(ChatError::NoPileTaken)
+        ));
+
+        log.push(GameEvent::Placed {
+            player_id: bill,
+            card: 7,
+            pile: 3,
+            taken: vec![4, 5],
+            points: 2,
+        });
+        log.push(GameEvent::Placed {
+            player_id: ted,
+            card: 60,
+            pile: 0,
+            taken: Vec::new(),
+            points: 0,
+        });
+        assert_eq!(pile_taker(Some(&log)).unwrap(), bill);
+
+        // Nobody has taken a pile in the next hand yet
+        log.push(deal);
+        assert!(matches!(
+            pile_taker(Some(&log)),
+            Err(ChatError::NoPileTaken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_state_waits_for_the_reveal() {
+        let room = Room::new("REVEAL".to_owned());
+        room.settings.lock().await.reveal_step_ms = Some(100);
+        {
+            let mut state = room.state.lock().await;
+            let (_, bill_id) = state.join("Bill".to_owned()).unwrap();
+            let (_, ted_id) = state.join("Ted".to_owned()).unwrap();
+            state.start_game().unwrap();
+            for id in [bill_id, ted_id] {
+                let card = state.view_for(id).unwrap().hand[0];
+                state.play_card(id, card).unwrap();
+            }
+        }
+        let unsent = || async {
+            let sent = room.events_sent.lock().await;
+            let state = room.state.lock().await;
+            has_unsent_public_events(&sent, state.log().unwrap())
+        };
+        assert!(unsent().await);
+
+        let start = Instant::now();
+        let broadcast = async {
+            // Let the reveal start first
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            room.broadcast_state().await;
+            start.elapsed()
+        };
+        let ((), broadcast_at) = tokio::join!(room.send_game_events(), broadcast);
+        assert!(broadcast_at >= Duration::from_millis(100));
+        assert!(!unsent().await);
+    }
+}
@@ -1,13 +1,17 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use rand::{seq::SliceRandom, thread_rng};
 use serde_json::{json, Value};
 
-#[derive(Debug)]
+use crate::bot::StrategyKind;
+use crate::history::{GameEvent, GameLog};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub enum State {
     Lobby(Lobby),
     Game(Game),
-    GameOver(PlayerMapping),
+    /// The final scores and the log of the game that was played
+    GameOver(PlayerMapping, GameLog),
 }
 
 impl State {
@@ -15,49 +19,152 @@ impl State {
         State::Lobby(Lobby::new())
     }
 
-    pub fn join(&mut self, name: String) -> Result<String, StateError> {
+    /// Join the lobby as a new player
+    ///
+    /// Returns the new player's secret session token and their public id
+    pub fn join(&mut self, name: String) -> Result<(String, PlayerId), StateError> {
         match self {
-            State::Lobby(l) => Ok(l.join(name)),
-            State::Game(_) => todo!("Handle error"),
-            State::GameOver(_) => todo!("Handle error"),
+            State::Lobby(l) => l.join(name),
+            State::Game(_) => Err(StateError::GameAlreadyStarted),
+            State::GameOver(..) => Err(StateError::GameOver),
         }
     }
 
     pub fn start_game(&mut self) -> Result<(), StateError> {
         match self {
             State::Lobby(l) => {
-                *self = State::Game(l.start_game().expect("TODO: handle error"));
+                *self = State::Game(l.start_game()?);
+                Ok(())
+            }
+            State::Game(_) => Err(StateError::GameAlreadyStarted),
+            State::GameOver(..) => Err(StateError::GameOver),
+        }
+    }
+
+    /// Start a new game with the same players and rules as the game that just finished
+    pub fn rematch(&mut self) -> Result<(), StateError> {
+        match self {
+            State::Lobby(_) => Err(StateError::GameNotStarted),
+            State::Game(_) => Err(StateError::GameNotOver),
+            State::GameOver(..) => {
+                self.restart();
+                self.start_game()
+            }
+        }
+    }
+
+    /// Go back to the lobby once the game is over
+    pub fn return_to_lobby(&mut self) -> Result<(), StateError> {
+        match self {
+            State::Lobby(_) => Err(StateError::GameNotStarted),
+            State::Game(_) => Err(StateError::GameNotOver),
+            State::GameOver(..) => {
+                self.restart();
                 Ok(())
             }
-            State::Game(_) => todo!("Handle error"),
-            State::GameOver(_) => todo!("Handle error"),
         }
     }
 
-    pub fn play_card(&mut self, user_id: &str, card: u8) -> Result<(), StateError> {
+    /// Check that `start_game` would succeed without starting the game
+    pub fn can_start(&self) -> Result<(), StateError> {
         match self {
-            State::Lobby(_) => todo!("Handle error"),
+            State::Lobby(l) => Ok(l.can_start()?),
+            State::Game(_) => Err(StateError::GameAlreadyStarted),
+            State::GameOver(..) => Err(StateError::GameOver),
+        }
+    }
+
+    /// Say whether a player in the lobby is ready for the game to start
+    pub fn set_ready(&mut self, player_id: PlayerId, ready: bool) -> Result<(), StateError> {
+        match self {
+            State::Lobby(l) => l.set_ready(player_id, ready),
+            State::Game(_) => Err(StateError::GameAlreadyStarted),
+            State::GameOver(..) => Err(StateError::GameOver),
+        }
+    }
+
+    /// Whether the game can start with every connected player ready
+    ///
+    /// Players who are not in `present` don't hold the game up but at least
+    /// one human must be there and ready.
+    pub fn everyone_ready(&self, present: &HashSet<PlayerId>) -> bool {
+        let State::Lobby(l) = self else {
+            return false;
+        };
+        let mut humans = l
+            .players
+            .players_iter()
+            .filter(|(id, p)| p.bot.is_none() && present.contains(id))
+            .peekable();
+        l.can_start().is_ok() && humans.peek().is_some() && humans.all(|(_, p)| p.ready)
+    }
+
+    pub fn play_card(&mut self, player_id: PlayerId, card: u8) -> Result<(), StateError> {
+        match self {
+            State::Lobby(_) => return Err(StateError::GameNotStarted),
+            State::Game(g) if g.paused => return Err(StateError::GamePaused),
             State::Game(g) => {
-                if g.play_card(user_id, card).expect("TODO: handle error") {
-                    *self = State::GameOver(g.player_mapping().clone())
+                if g.play_card(player_id, card)? {
+                    *self = State::GameOver(g.player_mapping().clone(), g.log.clone())
                 }
             }
-            State::GameOver(_) => todo!("Handle error"),
+            State::GameOver(..) => return Err(StateError::GameOver),
         }
         Ok(())
     }
 
-    pub fn select_pile(&mut self, user_id: &str, pile_index: PileIndex) -> Result<(), StateError> {
+    /// Take back the card a player played this round so they can play another
+    ///
+    /// This is only possible until everyone has played and the cards are revealed.
+    pub fn retract_card(&mut self, player_id: PlayerId) -> Result<(), StateError> {
+        match self {
+            State::Lobby(_) => Err(StateError::GameNotStarted),
+            State::Game(g) if g.paused => Err(StateError::GamePaused),
+            State::Game(g) => Ok(g.retract_card(player_id)?),
+            State::GameOver(..) => Err(StateError::GameOver),
+        }
+    }
+
+    pub fn select_pile(
+        &mut self,
+        player_id: PlayerId,
+        pile_index: PileIndex,
+    ) -> Result<(), StateError> {
         match self {
-            State::Lobby(_) => todo!("Handle error"),
+            State::Lobby(_) => return Err(StateError::GameNotStarted),
+            State::Game(g) if g.paused => return Err(StateError::GamePaused),
             State::Game(g) => {
-                if g.select_pile(user_id, pile_index)
-                    .expect("TODO: handle error")
-                {
-                    *self = State::GameOver(g.player_mapping().clone())
+                if g.select_pile(player_id, pile_index)? {
+                    *self = State::GameOver(g.player_mapping().clone(), g.log.clone())
                 }
             }
-            State::GameOver(_) => todo!("Handle error"),
+            State::GameOver(..) => return Err(StateError::GameOver),
+        }
+        Ok(())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        matches!(self, State::Game(g) if g.paused)
+    }
+
+    /// Check the game in progress can be paused or resumed
+    pub fn can_pause(&self, paused: bool) -> Result<(), StateError> {
+        match self {
+            State::Lobby(_) => Err(StateError::GameNotStarted),
+            State::Game(g) if g.paused == paused && paused => Err(StateError::GamePaused),
+            State::Game(g) if g.paused == paused => Err(StateError::NotPaused),
+            State::Game(_) => Ok(()),
+            State::GameOver(..) => Err(StateError::GameOver),
+        }
+    }
+
+    /// Pause or resume the game in progress
+    ///
+    /// Nobody can move while the game is paused.
+    pub fn set_paused(&mut self, paused: bool) -> Result<(), StateError> {
+        self.can_pause(paused)?;
+        if let State::Game(g) = self {
+            g.paused = paused;
         }
         Ok(())
     }
@@ -66,72 +173,518 @@ impl State {
         match self {
             State::Lobby(_) => {}
             State::Game(g) => {
-                let mut table = Table::new();
+                let mut table = Table::new(g.table.rules);
                 let mut players = g.player_mapping().clone();
-                players.reset(table.deck_mut());
+                players.reset(&mut table);
                 *self = State::Lobby(Lobby::new_from_parts(table, players))
             }
-            State::GameOver(p) => {
-                let mut table = Table::new();
+            State::GameOver(p, log) => {
+                let mut table = Table::new(log.rules);
                 let mut players = p.clone();
-                players.reset(table.deck_mut());
+                players.reset(&mut table);
                 *self = State::Lobby(Lobby::new_from_parts(table, players));
             }
         }
     }
 
-    pub fn serialize_for_user(&self, user_id: &str, online_users: &HashSet<String>) -> Value {
-        match self {
+    pub fn serialize_for_user(
+        &self,
+        player_id: PlayerId,
+        presence: &HashMap<PlayerId, Presence>,
+    ) -> Value {
+        self.serialize_for(Some(player_id), presence)
+    }
+
+    /// The state as seen by somebody watching without playing
+    ///
+    /// This never includes a hand or a card that has not been revealed yet.
+    pub fn serialize_for_spectator(&self, presence: &HashMap<PlayerId, Presence>) -> Value {
+        let mut response = self.serialize_for(None, presence);
+        response["spectator"] = json!(true);
+        response
+    }
+
+    /// The state as seen by a spectator who has only seen the first events of the game
+    ///
+    /// Spectators are kept behind the live game so they can't tell players
+    /// what is about to happen.
+    pub fn serialize_for_delayed_spectator(
+        &self,
+        visible_events: usize,
+        presence: &HashMap<PlayerId, Presence>,
+    ) -> Value {
+        let (players, log) = match self {
+            State::Lobby(_) => return self.serialize_for_spectator(presence),
+            State::Game(g) => (&g.players, &g.log),
+            State::GameOver(p, log) => (p, log),
+        };
+        if visible_events >= log.events.len() {
+            return self.serialize_for_spectator(presence);
+        }
+        // Show at least the deal so there is a table to look at
+        let replay = log.replay(visible_events.max(1) - 1).unwrap();
+        let players: Vec<_> = players
+            .players_iter()
+            .map(|(id, player)| {
+                let presence = player.presence(id, presence);
+                json!({
+                    "id": id,
+                    "name": player.name,
+                    "points": replay.points.get(&id).copied().unwrap_or_default(),
+                    "me": false,
+                    "played": if replay.played.contains_key(&id) { "played" } else { "must_play" },
+                    "online": matches!(presence, Presence::Online | Presence::Away),
+                    "afk": presence == Presence::Away,
+                    "presence": presence,
+                    "bot": player.bot,
+                    "stand_in": player.stand_in,
+                })
+            })
+            .collect();
+        json!({
+            "state": "game",
+            "players": players,
+            "hand_number": replay.hand,
+            "round": {
+                "number": replay.round,
+                "state": "play",
+                "played": null,
+            },
+            "piles": replay.piles,
+            "rules": log.rules,
+            "host": self.host(),
+            "paused": self.is_paused(),
+            "waiting": self.player_mapping().serialize_waiting(None),
+            "spectator": true,
+            "delayed": true,
+        })
+    }
+
+    fn serialize_for(
+        &self,
+        viewer: Option<PlayerId>,
+        presence: &HashMap<PlayerId, Presence>,
+    ) -> Value {
+        let mut response = match self {
             State::Lobby(l) => {
-                let players = l.players();
+                let players = l.serialize_players(viewer, presence);
                 json!({
                     "state": "lobby",
                     "players": players,
+                    "rules": l.table.rules,
+                    "max_players": l.table.rules.max_players(),
                 })
             }
             State::Game(g) => {
-                let hand = g.hand_for(user_id).unwrap();
                 let turn_state = match g.turn() {
                     Turn::CardPlay(_) => "play",
-                    Turn::PileSelection(i, _) if i == user_id => "select_pile",
+                    Turn::PileSelection(i, _) if Some(*i) == viewer => "select_pile",
                     Turn::PileSelection(_, _) => "other_select_pile",
                 };
-                let piles = g.piles().serialize();
-
-                json!({
+                let mut response = json!({
                     "state": "game",
-                    "players": g.serialize_players(user_id, online_users),
+                    "players": g.serialize_players(viewer, presence),
+                    "hand_number": g.hand,
                     "round": {
-                        "number": g.round(),
+                        "number": g.round().0,
                         "state": turn_state,
-                        "played": g.played_card_for(user_id),
+                        "played": null,
                     },
-                    "piles": piles,
-                    "hand": hand,
-                })
+                    "piles": g.piles().serialize(),
+                    "rules": g.table.rules,
+                });
+                if let Some(view) = viewer.and_then(|id| g.view_for(id)) {
+                    response["round"]["played"] = json!(view.played);
+                    response["hand"] = json!(view.hand);
+                }
+                response
             }
-            State::GameOver(p) => {
+            State::GameOver(p, log) => {
                 json!({
                     "state": "game_over",
-                    "players": p.player_scores()
+                    "players": p.player_scores(viewer, log)
                 })
             }
+        };
+        response["host"] = json!(self.host());
+        response["paused"] = json!(self.is_paused());
+        response["waiting"] = json!(self.player_mapping().serialize_waiting(viewer));
+        response
+    }
+
+    pub fn get_player(&self, player_id: PlayerId) -> Option<&Player> {
+        match self {
+            State::Lobby(l) => l.get_player(player_id),
+            State::Game(g) => g.get_player(player_id),
+            State::GameOver(p, _) => p.get(player_id),
+        }
+    }
+
+    /// The players who have to act before the game can continue
+    ///
+    /// Nobody has to act while the game is paused.
+    pub fn pending_players(&self) -> Vec<PlayerId> {
+        match self {
+            State::Game(g) if g.paused => Vec::new(),
+            State::Game(g) => g.pending_players(),
+            State::Lobby(_) | State::GameOver(..) => Vec::new(),
+        }
+    }
+
+    pub fn apply_move(&mut self, player_id: PlayerId, mv: Move) -> Result<(), StateError> {
+        match mv {
+            Move::PlayCard(card) => self.play_card(player_id, card),
+            Move::SelectPile(pile_index) => self.select_pile(player_id, pile_index),
+        }
+    }
+
+    /// The turn the game in progress is on
+    pub fn turn_id(&self) -> Option<TurnId> {
+        match self {
+            State::Game(g) => Some(TurnId {
+                game: g.log.id,
+                hand: g.hand,
+                round: g.round.0,
+                picker: match g.turn {
+                    Turn::CardPlay(_) => None,
+                    Turn::PileSelection(id, _) => Some(id),
+                },
+            }),
+            State::Lobby(_) | State::GameOver(..) => None,
+        }
+    }
+
+    /// What the player is allowed to see of the game in progress
+    pub fn view_for(&self, player_id: PlayerId) -> Option<PlayerView> {
+        match self {
+            State::Game(g) => g.view_for(player_id),
+            State::Lobby(_) | State::GameOver(..) => None,
+        }
+    }
+
+    /// The strategy making moves for this player if the seat is computer controlled
+    ///
+    /// This is either a bot's own strategy or a stand in playing for an absent player.
+    pub fn controller(&self, player_id: PlayerId) -> Option<StrategyKind> {
+        let player = self.get_player(player_id)?;
+        player.bot.or(player.stand_in)
+    }
+
+    /// Put a player on the waiting list to get a seat once one is free
+    ///
+    /// Returns the player's secret session token and the public id they keep
+    /// once they are seated
+    pub fn wait(&mut self, name: String) -> Result<(String, PlayerId), StateError> {
+        self.player_mapping_mut().wait(name)
+    }
+
+    /// Whether the player is on the waiting list
+    pub fn is_waiting(&self, player_id: PlayerId) -> bool {
+        self.player_mapping()
+            .waiting
+            .iter()
+            .any(|(id, _)| *id == player_id)
+    }
+
+    /// Let a player from the waiting list take over a seat in the game in progress
+    ///
+    /// The seat keeps its id, hand and points but gets the waiting player's
+    /// name and session while the old player's session is dropped.
+    pub fn take_seat(&mut self, seat: PlayerId, player_id: PlayerId) -> Result<(), StateError> {
+        let State::Game(g) = self else {
+            return Err(StateError::GameNotStarted);
+        };
+        g.players.take_seat(seat, player_id)
+    }
+
+    /// Change the rules the next game is played with
+    pub fn set_rules(&mut self, rules: RuleSet) -> Result<(), StateError> {
+        match self {
+            State::Lobby(l) => l.set_rules(rules),
+            State::Game(_) => Err(StateError::GameAlreadyStarted),
+            State::GameOver(..) => Err(StateError::GameOver),
+        }
+    }
+
+    /// Add a computer controlled player to the lobby
+    pub fn add_bot(&mut self, kind: StrategyKind) -> Result<PlayerId, StateError> {
+        match self {
+            State::Lobby(l) => l.add_bot(kind),
+            State::Game(_) => Err(StateError::GameAlreadyStarted),
+            State::GameOver(..) => Err(StateError::GameOver),
         }
     }
 
-    pub fn get_player(&self, user_id: &str) -> Option<&Player> {
+    /// Remove a computer controlled player from the lobby
+    pub fn remove_bot(&mut self, player_id: PlayerId) -> Result<(), StateError> {
         match self {
-            State::Lobby(l) => l.get_player(user_id),
-            State::Game(g) => g.get_player(user_id),
-            State::GameOver(p) => p.get(user_id),
+            State::Lobby(l) => l.remove_bot(player_id),
+            State::Game(_) => Err(StateError::GameAlreadyStarted),
+            State::GameOver(..) => Err(StateError::GameOver),
+        }
+    }
+
+    /// Let a strategy play for a human player until they are back (or stop it with `None`)
+    pub fn set_stand_in(
+        &mut self,
+        player_id: PlayerId,
+        stand_in: Option<StrategyKind>,
+    ) -> Result<(), StateError> {
+        let State::Game(g) = self else {
+            return Err(StateError::GameNotStarted);
+        };
+        let player = g
+            .players
+            .get_mut(player_id)
+            .ok_or(StateError::UnknownPlayer)?;
+        if player.bot.is_some() {
+            return Err(StateError::NotHuman);
+        }
+        player.stand_in = stand_in;
+        Ok(())
+    }
+
+    /// The player who controls the room
+    pub fn host(&self) -> Option<PlayerId> {
+        self.player_mapping().host()
+    }
+
+    /// Hand the host role to another human player
+    pub fn set_host(&mut self, player_id: PlayerId) -> Result<(), StateError> {
+        self.player_mapping_mut().set_host(player_id)
+    }
+
+    /// Remove a player from the lobby, returning their cards to the deck
+    pub fn kick(&mut self, by: PlayerId, player_id: PlayerId) -> Result<(), StateError> {
+        match self {
+            State::Lobby(l) => l.kick(by, player_id),
+            State::Game(_) => Err(StateError::GameAlreadyStarted),
+            State::GameOver(..) => Err(StateError::GameOver),
+        }
+    }
+
+    /// Every player in the room who is not a bot
+    pub fn human_players(&self) -> Vec<PlayerId> {
+        self.player_mapping()
+            .players_iter()
+            .filter(|(_, p)| p.bot.is_none())
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    /// Look up which player a session token belongs to
+    pub fn player_for_token(&self, token: &str) -> Option<PlayerId> {
+        self.player_mapping().player_for_token(token)
+    }
+
+    /// The log of the game in progress or the game that just finished
+    pub fn log(&self) -> Option<&GameLog> {
+        match self {
+            State::Lobby(_) => None,
+            State::Game(g) => Some(&g.log),
+            State::GameOver(_, log) => Some(log),
+        }
+    }
+
+    /// The log of the game if it has just finished
+    pub fn finished_log(&self) -> Option<&GameLog> {
+        match self {
+            State::GameOver(_, log) => Some(log),
+            State::Lobby(_) | State::Game(_) => None,
+        }
+    }
+
+    fn player_mapping(&self) -> &PlayerMapping {
+        match self {
+            State::Lobby(l) => &l.players,
+            State::Game(g) => g.player_mapping(),
+            State::GameOver(p, _) => p,
+        }
+    }
+
+    fn player_mapping_mut(&mut self) -> &mut PlayerMapping {
+        match self {
+            State::Lobby(l) => &mut l.players,
+            State::Game(g) => &mut g.players,
+            State::GameOver(p, _) => p,
         }
     }
 }
 
-#[derive(Debug)]
-pub enum StateError {}
+/// An error caused by a request that is not valid for the current state
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateError {
+    /// The request is only valid before the game has started
+    GameAlreadyStarted,
+    /// The request is only valid once the game has started
+    GameNotStarted,
+    /// The request is not valid once the game is over
+    GameOver,
+    /// The request is only valid once the game is over
+    GameNotOver,
+    /// Tried to start a game with too few players
+    NotEnoughPlayers {
+        players: usize,
+        min: usize,
+    },
+    /// The user is not a player in this game
+    UnknownPlayer,
+    /// The name is empty or too long
+    InvalidName,
+    /// Another player in the room already uses this name
+    NameTaken,
+    /// There are not enough cards left to deal another player a hand
+    TableFull,
+    /// The rules can't be played, for the reason given
+    InvalidRules(&'static str),
+    /// The player is a human but the action is only for bots
+    NotABot,
+    /// The player is a bot but the action is only for humans
+    NotHuman,
+    /// The seat is still held for its player
+    SeatNotAbandoned,
+    /// The action is not allowed in the current turn
+    WrongTurn,
+    /// The player already played a card this round
+    AlreadyPlayed,
+    /// The player does not have the card they tried to play
+    CardNotInHand,
+    /// There is no pile with this index
+    InvalidPileIndex(usize),
+    /// Another player is the one who has to select a pile
+    NotYourPileSelection,
+    /// Only the host of the room may do this
+    NotHost,
+    /// The host tried to kick themselves out of the room
+    CannotKickSelf,
+    /// The player has a seat or is not in the room at all
+    NotWaiting,
+    /// Players can only take one vote at a time
+    VoteInProgress,
+    /// There is no vote to take part in
+    NoVote,
+    /// The player was not in the room when the vote started
+    NotAVoter,
+    AlreadyVoted,
+    /// Nobody can move until the game is resumed
+    GamePaused,
+    /// The game can't be resumed because it isn't paused
+    NotPaused,
+    /// The player has no card to take back this round
+    NotPlayed,
+}
 
-#[derive(Debug)]
+impl StateError {
+    /// A stable machine readable code for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            StateError::GameAlreadyStarted => "game_already_started",
+            StateError::GameNotStarted => "game_not_started",
+            StateError::GameOver => "game_over",
+            StateError::GameNotOver => "game_not_over",
+            StateError::NotEnoughPlayers { .. } => "not_enough_players",
+            StateError::UnknownPlayer => "unknown_player",
+            StateError::InvalidName => "invalid_name",
+            StateError::NameTaken => "name_taken",
+            StateError::TableFull => "table_full",
+            StateError::InvalidRules(_) => "invalid_rules",
+            StateError::NotABot => "not_a_bot",
+            StateError::NotHuman => "not_human",
+            StateError::SeatNotAbandoned => "seat_not_abandoned",
+            StateError::WrongTurn => "wrong_turn",
+            StateError::AlreadyPlayed => "already_played",
+            StateError::CardNotInHand => "card_not_in_hand",
+            StateError::InvalidPileIndex(_) => "invalid_pile_index",
+            StateError::NotYourPileSelection => "not_your_pile_selection",
+            StateError::NotHost => "not_host",
+            StateError::CannotKickSelf => "cannot_kick_self",
+            StateError::NotWaiting => "not_waiting",
+            StateError::VoteInProgress => "vote_in_progress",
+            StateError::NoVote => "no_vote",
+            StateError::NotAVoter => "not_a_voter",
+            StateError::AlreadyVoted => "already_voted",
+            StateError::GamePaused => "game_paused",
+            StateError::NotPaused => "not_paused",
+            StateError::NotPlayed => "not_played",
+        }
+    }
+}
+
+impl std::fmt::Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateError::GameAlreadyStarted => write!(f, "The game has already started"),
+            StateError::GameNotStarted => write!(f, "The game has not started yet"),
+            StateError::GameOver => write!(f, "The game is over"),
+            StateError::GameNotOver => write!(f, "The game is not over yet"),
+            StateError::NotEnoughPlayers { players, min } => write!(
+                f,
+                "At least {min} players are needed to start but only {players} joined"
+            ),
+            StateError::UnknownPlayer => write!(f, "You are not a player in this game"),
+            StateError::InvalidName => write!(
+                f,
+                "Names must be between 1 and {MAX_NAME_LEN} characters long"
+            ),
+            StateError::NameTaken => write!(f, "Another player already has that name"),
+            StateError::TableFull => {
+                write!(f, "There are not enough cards left to deal you a hand")
+            }
+            StateError::InvalidRules(reason) => write!(f, "Those rules can't be played: {reason}"),
+            StateError::NotABot => write!(f, "That player is not a bot"),
+            StateError::NotHuman => write!(f, "That player is a bot"),
+            StateError::SeatNotAbandoned => {
+                write!(f, "That seat is still being held for its player")
+            }
+            StateError::WrongTurn => write!(f, "That is not allowed right now"),
+            StateError::AlreadyPlayed => write!(f, "You already played a card this round"),
+            StateError::CardNotInHand => write!(f, "That card is not in your hand"),
+            StateError::InvalidPileIndex(i) => write!(f, "There is no pile {i}"),
+            StateError::NotYourPileSelection => {
+                write!(f, "Another player has to select a pile")
+            }
+            StateError::NotHost => write!(f, "Only the host can do that"),
+            StateError::CannotKickSelf => write!(f, "You can't kick yourself"),
+            StateError::NotWaiting => write!(f, "You are not waiting for a seat"),
+            StateError::VoteInProgress => write!(f, "Another vote is already going on"),
+            StateError::NoVote => write!(f, "There is nothing to vote on"),
+            StateError::NotAVoter => write!(f, "You can't vote on this"),
+            StateError::AlreadyVoted => write!(f, "You already voted"),
+            StateError::GamePaused => write!(f, "The game is paused"),
+            StateError::NotPaused => write!(f, "The game is not paused"),
+            StateError::NotPlayed => write!(f, "You haven't played a card this round"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+impl From<GameStartError> for StateError {
+    fn from(e: GameStartError) -> Self {
+        match e {
+            GameStartError::NotEnoughPlayers(players) => StateError::NotEnoughPlayers {
+                players,
+                min: MIN_PLAYERS,
+            },
+        }
+    }
+}
+
+impl From<PlacementError> for StateError {
+    fn from(e: PlacementError) -> Self {
+        match e {
+            PlacementError::PlacementOutOfTurn => StateError::WrongTurn,
+            PlacementError::RepeatedPlacement => StateError::AlreadyPlayed,
+            PlacementError::NothingPlaced => StateError::NotPlayed,
+            PlacementError::CardNotInHand => StateError::CardNotInHand,
+            PlacementError::NoUser => StateError::UnknownPlayer,
+            PlacementError::NotPlayersSelection => StateError::NotYourPileSelection,
+            PlacementError::NoPile(i) => StateError::InvalidPileIndex(i.into()),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct Lobby {
     table: Table,
     players: PlayerMapping,
@@ -139,135 +692,546 @@ pub struct Lobby {
 
 impl Lobby {
     fn new() -> Self {
-        Self::new_from_parts(Table::new(), PlayerMapping::new())
+        Self::new_from_parts(Table::new(RuleSet::default()), PlayerMapping::new())
     }
 
     fn new_from_parts(table: Table, players: PlayerMapping) -> Lobby {
         Self { table, players }
     }
 
-    fn join(&mut self, name: String) -> String {
-        self.players.join(name, &mut self.table.deck)
+    fn join(&mut self, name: String) -> Result<(String, PlayerId), StateError> {
+        self.players.join(name, &mut self.table)
     }
 
-    fn start_game(&mut self) -> Result<Game, GameStartError> {
+    /// Switch to new rules, dealing everyone a new hand
+    fn set_rules(&mut self, rules: RuleSet) -> Result<(), StateError> {
+        rules.validate(self.players.num())?;
+        let mut table = Table::new(rules);
+        self.players.reset(&mut table);
+        self.table = table;
+        Ok(())
+    }
+
+    fn add_bot(&mut self, kind: StrategyKind) -> Result<PlayerId, StateError> {
+        let name = (1..)
+            .map(|n| format!("{} {n}", kind.display_name()))
+            .find(|name| !self.players.name_taken(name))
+            .unwrap();
+        self.players.add_bot(name, kind, &mut self.table)
+    }
+
+    fn remove_bot(&mut self, player_id: PlayerId) -> Result<(), StateError> {
+        match self.players.get(player_id) {
+            Some(p) if p.bot.is_some() => {}
+            Some(_) => return Err(StateError::NotABot),
+            None => return Err(StateError::UnknownPlayer),
+        }
+        self.players.remove(player_id, &mut self.table.deck);
+        self.players.seat_waiting(&mut self.table);
+        Ok(())
+    }
+
+    fn kick(&mut self, by: PlayerId, player_id: PlayerId) -> Result<(), StateError> {
+        if by == player_id {
+            return Err(StateError::CannotKickSelf);
+        }
+        self.players
+            .remove(player_id, &mut self.table.deck)
+            .ok_or(StateError::UnknownPlayer)?;
+        self.players.seat_waiting(&mut self.table);
+        Ok(())
+    }
+
+    fn set_ready(&mut self, player_id: PlayerId, ready: bool) -> Result<(), StateError> {
+        let player = self
+            .players
+            .get_mut(player_id)
+            .ok_or(StateError::UnknownPlayer)?;
+        if player.bot.is_some() {
+            return Err(StateError::NotHuman);
+        }
+        player.ready = ready;
+        Ok(())
+    }
+
+    fn can_start(&self) -> Result<(), GameStartError> {
         if self.players.num() < MIN_PLAYERS {
             return Err(GameStartError::NotEnoughPlayers(self.players.num()));
         }
-        let table = std::mem::replace(&mut self.table, Table::new());
+        Ok(())
+    }
+
+    fn start_game(&mut self) -> Result<Game, GameStartError> {
+        self.can_start()?;
+        let rules = self.table.rules;
+        let table = std::mem::replace(&mut self.table, Table::new(rules));
         let players = std::mem::replace(&mut self.players, PlayerMapping::new());
         Ok(Game::new(table, players))
     }
 
+    #[cfg(test)]
     fn players(&self) -> Vec<String> {
         self.players.players()
     }
 
-    fn get_player(&self, id: &str) -> Option<&Player> {
+    fn get_player(&self, id: PlayerId) -> Option<&Player> {
         self.players.get(id)
     }
+
+    fn serialize_players(
+        &self,
+        viewer: Option<PlayerId>,
+        presence: &HashMap<PlayerId, Presence>,
+    ) -> Vec<Value> {
+        self.players
+            .players_iter()
+            .map(|(id, player)| {
+                let presence = player.presence(id, presence);
+                json!({
+                    "id": id,
+                    "name": player.name,
+                    "me": Some(id) == viewer,
+                    "online": matches!(presence, Presence::Online | Presence::Away),
+                    "afk": presence == Presence::Away,
+                    "presence": presence,
+                    "bot": player.bot,
+                    "ready": player.ready || player.bot.is_some(),
+                })
+            })
+            .collect()
+    }
 }
 
 const MIN_PLAYERS: usize = 2;
 
+/// The rules a game is played with
+#[derive(serde::Serialize, serde::Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RuleSet {
+    /// How many cards each player is dealt
+    pub hand_size: usize,
+    /// How many rounds are played before the game is over
+    pub rounds: u8,
+    /// How many piles there are on the table
+    pub piles: usize,
+    /// How many cards a pile holds before the next card placed on it takes the pile
+    pub pile_capacity: usize,
+    /// Keep dealing new hands until a player has this many points, or play a
+    /// single hand if not set
+    pub match_points: Option<u16>,
+}
+
+impl Default for RuleSet {
+    /// The standard 6 nimmt! rules
+    fn default() -> Self {
+        Self {
+            hand_size: 10,
+            rounds: 10,
+            piles: 4,
+            pile_capacity: 5,
+            match_points: None,
+        }
+    }
+}
+
+impl RuleSet {
+    /// How many players can be dealt a hand with cards left over to start the piles
+    pub fn max_players(&self) -> usize {
+        DECK_SIZE.saturating_sub(self.piles) / self.hand_size.max(1)
+    }
+
+    /// Check that a game with this many players can be played with the rules
+    fn validate(&self, players: usize) -> Result<(), StateError> {
+        if self.hand_size == 0 || self.rounds == 0 || self.piles == 0 || self.pile_capacity == 0 {
+            return Err(StateError::InvalidRules(
+                "hand size, rounds, piles and pile capacity must all be at least 1",
+            ));
+        }
+        if self.rounds as usize > self.hand_size {
+            return Err(StateError::InvalidRules(
+                "there can't be more rounds than cards in a hand",
+            ));
+        }
+        if self.match_points == Some(0) {
+            return Err(StateError::InvalidRules(
+                "a match must be played to at least 1 point",
+            ));
+        }
+        if players.max(MIN_PLAYERS) > self.max_players() {
+            return Err(StateError::InvalidRules(
+                "there are not enough cards to deal every player a hand",
+            ));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 enum GameStartError {
     NotEnoughPlayers(usize),
 }
 
-#[derive(Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 struct Table {
     deck: Deck,
     piles: Piles,
+    rules: RuleSet,
 }
 
 impl Table {
-    fn new() -> Self {
+    fn new(rules: RuleSet) -> Self {
         let mut deck = Deck::new();
         Self {
-            piles: Piles::new(&mut deck),
+            piles: Piles::new(&mut deck, rules.piles),
             deck,
+            rules,
         }
     }
 
-    fn deck_mut(&mut self) -> &mut Deck {
-        &mut self.deck
+    /// Deal a hand from the deck, or `None` if there are not enough cards left
+    fn deal_hand(&mut self) -> Option<Vec<u8>> {
+        if self.deck.cards.len() < self.rules.hand_size {
+            return None;
+        }
+        let mut hand: Vec<_> = (0..self.rules.hand_size)
+            .map(|_| self.deck.deal())
+            .collect();
+        hand.sort();
+        Some(hand)
     }
 }
 
-/// A mapping of IDs to player names
-#[derive(Debug, Clone)]
-pub struct PlayerMapping(HashMap<String, Player>);
+/// The public id of a player
+///
+/// Unlike the session token this is safe to show to other players.
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+pub struct PlayerId(u32);
+
+impl From<u32> for PlayerId {
+    fn from(id: u32) -> Self {
+        Self(id)
+    }
+}
+
+impl std::fmt::Display for PlayerId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Whether a player is currently connected to the room
+#[derive(serde::Serialize, Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Presence {
+    Online,
+    /// The player is connected but hasn't done anything for a while
+    Away,
+    /// The player dropped but their seat is held for them
+    Reconnecting,
+    #[default]
+    Offline,
+}
+
+/// The longest name a player may have
+const MAX_NAME_LEN: usize = 32;
+
+/// The players in a game keyed by their public id
+///
+/// Players authenticate with a secret session token which is mapped to their
+/// public id.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct PlayerMapping {
+    players: BTreeMap<PlayerId, Player>,
+    sessions: HashMap<String, PlayerId>,
+    next_id: u32,
+    /// The player who controls the room
+    #[serde(default)]
+    host: Option<PlayerId>,
+    /// Players without a seat who get the next free one, in the order they came
+    #[serde(default)]
+    waiting: Vec<(PlayerId, String)>,
+}
 
 impl PlayerMapping {
     fn new() -> PlayerMapping {
-        PlayerMapping(HashMap::new())
+        PlayerMapping {
+            players: BTreeMap::new(),
+            sessions: HashMap::new(),
+            next_id: 0,
+            host: None,
+            waiting: Vec::new(),
+        }
+    }
+
+    /// The host, falling back to the human who joined first
+    fn host(&self) -> Option<PlayerId> {
+        self.host
+            .filter(|id| self.players.contains_key(id))
+            .or_else(|| {
+                self.players_iter()
+                    .find(|(_, p)| p.bot.is_none())
+                    .map(|(id, _)| id)
+            })
     }
 
-    fn reset(&mut self, deck: &mut Deck) {
-        for player in self.0.values_mut() {
-            *player = Player::new(std::mem::replace(&mut player.name, String::new()), deck)
+    fn set_host(&mut self, player_id: PlayerId) -> Result<(), StateError> {
+        match self.get(player_id) {
+            Some(p) if p.bot.is_some() => return Err(StateError::NotHuman),
+            Some(_) => {}
+            None => return Err(StateError::UnknownPlayer),
         }
+        self.host = Some(player_id);
+        Ok(())
+    }
+
+    fn reset(&mut self, table: &mut Table) {
+        for player in self.players.values_mut() {
+            let hand = table
+                .deal_hand()
+                .expect("rules should leave enough cards for every player");
+            *player = Player {
+                bot: player.bot,
+                ..Player::new(std::mem::take(&mut player.name), hand)
+            }
+        }
+        self.seat_waiting(table);
+    }
+
+    /// Give free seats to players on the waiting list
+    fn seat_waiting(&mut self, table: &mut Table) {
+        while !self.waiting.is_empty() && self.num() < table.rules.max_players() {
+            let Some(hand) = table.deal_hand() else {
+                break;
+            };
+            let (id, name) = self.waiting.remove(0);
+            self.players.insert(id, Player::new(name, hand));
+            if self.host.is_none() {
+                self.host = Some(id);
+            }
+        }
+    }
+
+    fn take_seat(&mut self, seat: PlayerId, player_id: PlayerId) -> Result<(), StateError> {
+        match self.get(seat) {
+            Some(p) if p.bot.is_some() => return Err(StateError::NotHuman),
+            Some(_) => {}
+            None => return Err(StateError::UnknownPlayer),
+        }
+        let i = self
+            .waiting
+            .iter()
+            .position(|(id, _)| *id == player_id)
+            .ok_or(StateError::NotWaiting)?;
+        let (_, name) = self.waiting.remove(i);
+        self.sessions.retain(|_, id| *id != seat);
+        for id in self.sessions.values_mut() {
+            if *id == player_id {
+                *id = seat;
+            }
+        }
+        let player = self.players.get_mut(&seat).unwrap();
+        player.name = name;
+        player.stand_in = None;
+        Ok(())
     }
 
+    /// Add a player to the waiting list, returning their session token and public id
+    fn wait(&mut self, name: String) -> Result<(String, PlayerId), StateError> {
+        let name = normalize_name(&name)?;
+        if self.name_taken(&name) {
+            return Err(StateError::NameTaken);
+        }
+        let id = self.next_player_id();
+        let token = new_session_token();
+        self.waiting.push((id, name));
+        self.sessions.insert(token.clone(), id);
+        Ok((token, id))
+    }
+
+    fn serialize_waiting(&self, viewer: Option<PlayerId>) -> Vec<Value> {
+        self.waiting
+            .iter()
+            .map(|(id, name)| {
+                json!({
+                    "id": id,
+                    "name": name,
+                    "me": Some(*id) == viewer,
+                })
+            })
+            .collect()
+    }
+
+    #[cfg(test)]
     fn players(&self) -> Vec<String> {
-        self.0.values().map(|p| p.name.clone()).collect()
+        self.players.values().map(|p| p.name.clone()).collect()
+    }
+
+    /// Add a player, returning their session token and public id
+    fn join(&mut self, name: String, table: &mut Table) -> Result<(String, PlayerId), StateError> {
+        let name = normalize_name(&name)?;
+        if self.name_taken(&name) {
+            return Err(StateError::NameTaken);
+        }
+        if self.num() >= table.rules.max_players() {
+            return Err(StateError::TableFull);
+        }
+
+        let hand = table.deal_hand().ok_or(StateError::TableFull)?;
+        let id = self.next_player_id();
+        let token = new_session_token();
+        self.players.insert(id, Player::new(name, hand));
+        self.sessions.insert(token.clone(), id);
+        if self.host.is_none() {
+            self.host = Some(id);
+        }
+        Ok((token, id))
     }
 
-    fn join(&mut self, name: String, deck: &mut Deck) -> String {
-        use std::hash::{Hash, Hasher};
-        let mut hasher = std::collections::hash_map::DefaultHasher::new();
-        name.hash(&mut hasher);
-        let key = hasher.finish();
+    /// Add a bot which has a seat but no session
+    fn add_bot(
+        &mut self,
+        name: String,
+        kind: StrategyKind,
+        table: &mut Table,
+    ) -> Result<PlayerId, StateError> {
+        if self.name_taken(&name) {
+            return Err(StateError::NameTaken);
+        }
+        if self.num() >= table.rules.max_players() {
+            return Err(StateError::TableFull);
+        }
+        let hand = table.deal_hand().ok_or(StateError::TableFull)?;
+        let id = self.next_player_id();
+        let player = Player {
+            bot: Some(kind),
+            ..Player::new(name, hand)
+        };
+        self.players.insert(id, player);
+        Ok(id)
+    }
+
+    /// Remove a player, returning their cards to the deck
+    fn remove(&mut self, player_id: PlayerId, deck: &mut Deck) -> Option<Player> {
+        let player = self.players.remove(&player_id)?;
+        self.sessions.retain(|_, id| *id != player_id);
+        if self.host == Some(player_id) {
+            self.host = None;
+            self.host = self.host();
+        }
+        deck.return_cards(&player.hand);
+        Some(player)
+    }
 
-        // TODO: handle if the player was already added
-        self.0.insert(key.to_string(), Player::new(name, deck));
-        key.to_string()
+    fn name_taken(&self, name: &str) -> bool {
+        self.players
+            .values()
+            .map(|p| &p.name)
+            .chain(self.waiting.iter().map(|(_, name)| name))
+            .any(|n| n.to_lowercase() == name.to_lowercase())
+    }
+
+    fn next_player_id(&mut self) -> PlayerId {
+        let id = PlayerId(self.next_id);
+        self.next_id += 1;
+        id
     }
 
     fn num(&self) -> usize {
-        self.0.len()
+        self.players.len()
     }
 
-    fn get(&self, user_id: &str) -> Option<&Player> {
-        self.0.get(user_id)
+    fn get(&self, player_id: PlayerId) -> Option<&Player> {
+        self.players.get(&player_id)
     }
 
-    fn get_mut(&mut self, user_id: &str) -> Option<&mut Player> {
-        self.0.get_mut(user_id)
+    fn get_mut(&mut self, player_id: PlayerId) -> Option<&mut Player> {
+        self.players.get_mut(&player_id)
     }
 
-    fn players_iter(&self) -> impl Iterator<Item = (&String, &Player)> {
-        self.0.iter()
+    fn player_for_token(&self, token: &str) -> Option<PlayerId> {
+        self.sessions.get(token).copied()
     }
 
-    fn player_scores(&self) -> HashMap<String, u16> {
-        self.0
-            .values()
-            .map(|p| (p.name.clone(), p.points))
+    fn players_iter(&self) -> impl Iterator<Item = (PlayerId, &Player)> {
+        self.players.iter().map(|(id, p)| (*id, p))
+    }
+
+    fn player_scores(&self, viewer: Option<PlayerId>, log: &GameLog) -> Vec<Value> {
+        self.players_iter()
+            .map(|(id, p)| {
+                json!({
+                    "id": id,
+                    "name": p.name,
+                    "points": p.points,
+                    "hands": log.hand_points(id),
+                    "me": Some(id) == viewer,
+                })
+            })
             .collect()
     }
 }
 
-#[derive(Debug, Clone)]
+/// Trim a name given by a user, checking it is not empty or too long
+pub fn normalize_name(name: &str) -> Result<String, StateError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(StateError::InvalidName);
+    }
+    Ok(name.to_owned())
+}
+
+/// Generate a random, unguessable session token
+fn new_session_token() -> String {
+    use rand::RngCore;
+    let mut bytes = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Player {
     name: String,
     points: u16,
     hand: Vec<u8>,
+    /// The strategy of a bot player
+    bot: Option<StrategyKind>,
+    /// The strategy playing in place of an absent human player
+    stand_in: Option<StrategyKind>,
+    /// Whether the player is ready for the game to start, bots always are
+    #[serde(default)]
+    ready: bool,
 }
 
 impl Player {
-    fn new(name: String, deck: &mut Deck) -> Self {
-        let mut hand: Vec<_> = (0..10).into_iter().map(|_| deck.deal()).collect();
-        hand.sort();
+    fn new(name: String, hand: Vec<u8>) -> Self {
         Self {
             name,
             points: 0,
             hand,
+            bot: None,
+            stand_in: None,
+            ready: false,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The presence of the player, counting bots as always online
+    fn presence(&self, id: PlayerId, presence: &HashMap<PlayerId, Presence>) -> Presence {
+        if self.bot.is_some() {
+            return Presence::Online;
         }
+        presence.get(&id).copied().unwrap_or_default()
     }
 }
 
+/// How many cards there are, numbered from 1
+pub const DECK_SIZE: usize = 104;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
 struct Deck {
     cards: Vec<u8>,
 }
@@ -282,7 +1246,7 @@ impl std::fmt::Debug for Deck {
 
 impl Deck {
     fn new() -> Self {
-        let mut cards: Vec<u8> = (1..=104u8).collect();
+        let mut cards: Vec<u8> = (1..=DECK_SIZE as u8).collect();
         cards.shuffle(&mut thread_rng());
         Self { cards }
     }
@@ -290,48 +1254,72 @@ impl Deck {
     fn deal(&mut self) -> u8 {
         self.cards.pop().expect("Deck should never be fully dealt")
     }
+
+    /// Put cards back into the deck and reshuffle
+    fn return_cards(&mut self, cards: &[u8]) {
+        self.cards.extend_from_slice(cards);
+        self.cards.shuffle(&mut thread_rng());
+    }
 }
 
 /// The card piles
 ///
 /// The piles are always kept in sorted order
-#[derive(Debug)]
-struct Piles([Pile; 4]);
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+struct Piles(Vec<Pile>);
 
 impl Piles {
-    fn new(deck: &mut Deck) -> Self {
-        let mut piles = [deck.deal(), deck.deal(), deck.deal(), deck.deal()];
-        piles.sort();
-        let piles = [
-            Pile::new(PileIndex::Zero, piles[0]),
-            Pile::new(PileIndex::One, piles[1]),
-            Pile::new(PileIndex::Two, piles[2]),
-            Pile::new(PileIndex::Three, piles[3]),
-        ];
+    fn new(deck: &mut Deck, count: usize) -> Self {
+        let mut cards: Vec<u8> = (0..count).map(|_| deck.deal()).collect();
+        cards.sort();
+        let piles = cards
+            .into_iter()
+            .enumerate()
+            .map(|(i, card)| Pile::new(PileIndex(i), card))
+            .collect();
+        let mut s = Self(piles);
+        s.sort();
+        s
+    }
+
+    /// Rebuild piles from their cards ordered by pile index
+    fn from_cards(piles: &[Vec<u8>]) -> Self {
+        let piles = piles
+            .iter()
+            .enumerate()
+            .map(|(i, cards)| Pile {
+                index: PileIndex(i),
+                cards: cards.clone(),
+            })
+            .collect();
         let mut s = Self(piles);
         s.sort();
         s
     }
 
-    fn place(&mut self, card: u8) -> Option<Option<u16>> {
+    /// Place a card on the pile it belongs to
+    ///
+    /// Returns the index of that pile and the old pile if the card took it
+    fn place(&mut self, card: u8, capacity: usize) -> Option<(PileIndex, Option<Pile>)> {
         let pile = self.pile_for_card_mut(card)?;
-        let points = pile.place(card);
+        let index = pile.index;
+        let taken = pile.place(card, capacity);
         self.sort();
-        Some(points)
+        Some((index, taken))
     }
 
     fn can_place(&self, card: u8) -> bool {
         self.pile_for_card(card).is_some()
     }
 
-    fn replace_pile(&mut self, pile_index: PileIndex, card: u8) -> u16 {
+    fn replace_pile(&mut self, pile_index: PileIndex, card: u8) -> Pile {
         let old = std::mem::replace(self.get_mut(pile_index), Pile::new(pile_index, card));
         self.sort();
-        old.points()
+        old
     }
 
     fn sort(&mut self) {
-        self.0.sort_by(|p1, p2| p1.top_card().cmp(&p2.top_card()));
+        self.0.sort_by_key(|p| p.top_card());
     }
 
     fn pile_for_card(&self, card: u8) -> Option<&Pile> {
@@ -352,40 +1340,118 @@ impl Piles {
             .last()
     }
 
+    fn contains(&self, pile_index: PileIndex) -> bool {
+        self.0.iter().any(|p| p.index == pile_index)
+    }
+
     fn get_mut(&mut self, pile_index: PileIndex) -> &mut Pile {
         self.0.iter_mut().find(|p| p.index == pile_index).unwrap()
     }
 
     fn serialize(&self) -> Vec<&[u8]> {
         let mut piles = self.0.iter().collect::<Vec<&Pile>>();
-        piles.sort_by(|p1, p2| p1.index.cmp(&p2.index));
+        piles.sort_by_key(|p| p.index);
         piles.into_iter().map(|p| p.cards.as_slice()).collect()
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
-pub enum PileIndex {
-    Zero = 0,
-    One,
-    Two,
-    Three,
+/// The position of a pile on the table, counting from 0
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct PileIndex(usize);
+
+impl From<usize> for PileIndex {
+    fn from(index: usize) -> Self {
+        Self(index)
+    }
+}
+
+impl From<PileIndex> for usize {
+    fn from(index: PileIndex) -> Self {
+        index.0
+    }
 }
 
-impl TryFrom<usize> for PileIndex {
-    type Error = ();
+/// A game where every card is known, used by bots to play out possible futures
+///
+/// The player the simulation is run for is always `Simulation::ME` and the
+/// opponents are numbered from 1.
+#[derive(Debug, Clone)]
+pub struct Simulation(Game);
 
-    fn try_from(value: usize) -> Result<Self, Self::Error> {
-        match value {
-            0 => Ok(Self::Zero),
-            1 => Ok(Self::One),
-            2 => Ok(Self::Two),
-            3 => Ok(Self::Three),
-            _ => Err(()),
+impl Simulation {
+    pub const ME: PlayerId = PlayerId(0);
+
+    /// Start a round from the point of view of a player, given a guess of
+    /// every opponent's hand
+    pub fn new(view: &PlayerView, opponent_hands: Vec<Vec<u8>>) -> Self {
+        let mut players = PlayerMapping::new();
+        let hands = std::iter::once(view.hand.clone()).chain(opponent_hands);
+        for (i, hand) in hands.enumerate() {
+            let id = players.next_player_id();
+            let player = Player {
+                name: i.to_string(),
+                points: 0,
+                hand,
+                bot: None,
+                stand_in: None,
+                ready: false,
+            };
+            players.players.insert(id, player);
         }
+        let table = Table {
+            deck: Deck { cards: Vec::new() },
+            piles: Piles::from_cards(&view.piles),
+            // Simulations only ever play out the current hand
+            rules: RuleSet {
+                match_points: None,
+                ..view.rules
+            },
+        };
+        Simulation(Game {
+            table,
+            players,
+            turn: Turn::CardPlay(CardPlay::new()),
+            hand: 1,
+            round: Round(view.round),
+            revealed: view.revealed.clone(),
+            log: GameLog::new(view.rules, BTreeMap::new()),
+            paused: false,
+        })
+    }
+
+    pub fn opponents(&self) -> impl Iterator<Item = PlayerId> + '_ {
+        self.0
+            .players
+            .players_iter()
+            .map(|(id, _)| id)
+            .filter(|id| *id != Self::ME)
+    }
+
+    pub fn pending_players(&self) -> Vec<PlayerId> {
+        self.0.pending_players()
+    }
+
+    pub fn view_for(&self, player_id: PlayerId) -> PlayerView {
+        self.0.view_for(player_id).unwrap()
+    }
+
+    /// Make a move, returning whether the hand is over
+    pub fn apply_move(&mut self, player_id: PlayerId, mv: Move) -> bool {
+        let result = match mv {
+            Move::PlayCard(card) => self.0.play_card(player_id, card),
+            Move::SelectPile(pile_index) => self.0.select_pile(player_id, pile_index),
+        };
+        result.expect("simulated moves should always be legal")
+    }
+
+    pub fn points(&self, player_id: PlayerId) -> u16 {
+        self.0.players.get(player_id).map(|p| p.points).unwrap()
     }
 }
 
-#[derive(Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 struct Pile {
     index: PileIndex,
     cards: Vec<u8>,
@@ -405,11 +1471,10 @@ impl Pile {
 
     // Places the card in the pile
     //
-    // Returns `Some` if pile was converted to points
-    fn place(&mut self, card: u8) -> Option<u16> {
-        if self.cards.len() == 5 {
-            let old = std::mem::replace(self, Pile::new(self.index, card));
-            Some(old.points())
+    // Returns the old pile if it has to be taken by the player
+    fn place(&mut self, card: u8, capacity: usize) -> Option<Pile> {
+        if self.cards.len() >= capacity {
+            Some(std::mem::replace(self, Pile::new(self.index, card)))
         } else {
             self.cards.push(card);
             None
@@ -418,16 +1483,7 @@ impl Pile {
 
     /// Given a pile calculate how much that pile's points are
     fn points(&self) -> u16 {
-        self.cards
-            .iter()
-            .map(|card| match (card % 11, card % 5) {
-                (0, _) if *card == 55 => 6,
-                (0, _) => 5,
-                (_, 0) if card % 10 == 0 => 3,
-                (_, 0) => 2,
-                _ => 1,
-            })
-            .sum()
+        self.cards.iter().map(|card| card_points(*card)).sum()
     }
 
     #[cfg(test)]
@@ -436,37 +1492,167 @@ impl Pile {
     }
 }
 
-#[derive(Debug)]
+/// How many points (bullheads) a card is worth
+pub fn card_points(card: u8) -> u16 {
+    match (card % 11, card % 5) {
+        (0, _) if card == 55 => 6,
+        (0, _) => 5,
+        (_, 0) if card.is_multiple_of(10) => 3,
+        (_, 0) => 2,
+        _ => 1,
+    }
+}
+
+/// Everything a single player is allowed to know about a game in progress
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerView {
+    pub hand: Vec<u8>,
+    /// The cards of each pile ordered by pile index
+    pub piles: Vec<Vec<u8>>,
+    pub round: u8,
+    /// The card the player played this round if they have played
+    pub played: Option<u8>,
+    /// Whether the player has to select a pile
+    pub must_select_pile: bool,
+    /// Every card that has been revealed on the table so far this game
+    pub revealed: Vec<u8>,
+    /// The cards the other players played this round once they have been
+    /// revealed and are waiting for a pile to be selected, lowest first
+    pub round_plays: Vec<u8>,
+    pub num_players: usize,
+    pub rules: RuleSet,
+}
+
+/// Identifies a turn so it can be told apart from the turns before and after it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TurnId {
+    /// The id of the game's log
+    pub game: u64,
+    pub hand: usize,
+    pub round: u8,
+    /// The player who has to select a pile, if the turn is a pile selection
+    pub picker: Option<PlayerId>,
+}
+
+/// A single decision made by a player
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Move {
+    PlayCard(u8),
+    SelectPile(PileIndex),
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Game {
     table: Table,
     players: PlayerMapping,
     turn: Turn,
+    /// Which hand of the match is being played, counting from 1
+    hand: usize,
     round: Round,
+    /// Every card revealed on the table so far this hand
+    revealed: Vec<u8>,
+    log: GameLog,
+    #[serde(default)]
+    paused: bool,
 }
 
 impl Game {
     fn new(table: Table, players: PlayerMapping) -> Game {
-        Game {
+        let revealed = table.piles.0.iter().map(|p| p.top_card()).collect();
+        let names = players
+            .players_iter()
+            .map(|(id, p)| (id, p.name.clone()))
+            .collect();
+        let log = GameLog::new(table.rules, names);
+        let mut game = Game {
             table,
             players,
             turn: Turn::CardPlay(CardPlay::new()),
+            hand: 1,
             round: Round(1),
+            revealed,
+            log,
+            paused: false,
+        };
+        game.log_deal();
+        game
+    }
+
+    fn log_deal(&mut self) {
+        self.log.push(GameEvent::Deal {
+            hands: self
+                .players
+                .players_iter()
+                .map(|(id, p)| (id, p.hand.clone()))
+                .collect(),
+            piles: self
+                .table
+                .piles
+                .serialize()
+                .into_iter()
+                .map(<[u8]>::to_vec)
+                .collect(),
+        });
+    }
+
+    /// Move on to the next round, ending the hand after the last one
+    ///
+    /// Returns whether the game is over
+    fn next_round(&mut self) -> bool {
+        self.turn = Turn::CardPlay(CardPlay::new());
+        self.round.inc(self.table.rules.rounds) && self.end_hand()
+    }
+
+    /// Score the hand and deal the next one unless the match is over
+    ///
+    /// Returns whether the game is over
+    fn end_hand(&mut self) -> bool {
+        let points = self
+            .players
+            .players_iter()
+            .map(|(id, p)| (id, p.points - self.log.hand_points(id).iter().sum::<u16>()))
+            .collect();
+        self.log.push(GameEvent::HandOver {
+            hand: self.hand,
+            points,
+        });
+
+        let over = match self.table.rules.match_points {
+            Some(max) => self.players.players_iter().any(|(_, p)| p.points >= max),
+            None => true,
+        };
+        if over {
+            return true;
+        }
+
+        let mut table = Table::new(self.table.rules);
+        for player in self.players.players.values_mut() {
+            player.hand = table
+                .deal_hand()
+                .expect("rules should leave enough cards for every player");
         }
+        self.revealed = table.piles.0.iter().map(|p| p.top_card()).collect();
+        self.table = table;
+        self.hand += 1;
+        self.round = Round(1);
+        self.log_deal();
+        false
     }
 
     /// Play a card as a user
     ///
     /// Returns `Ok(true)` if the game is over
-    fn play_card(&mut self, user_id: &str, card: u8) -> Result<bool, PlacementError> {
+    fn play_card(&mut self, player_id: PlayerId, card: u8) -> Result<bool, PlacementError> {
         enum NextStep {
-            PileSelection(String, CardPlay),
+            PileSelection(PlayerId, CardPlay),
             ApplyPlay(CardPlay),
         }
         let next_step = match &mut self.turn {
             Turn::CardPlay(p) => {
-                match self.players.get(user_id) {
+                match self.players.get(player_id) {
                     Some(player) if player.hand.contains(&card) => {
-                        p.play_card(user_id.to_owned(), card)?;
+                        p.play_card(player_id, card)?;
+                        self.log.push(GameEvent::CardPlayed { player_id, card });
                     }
                     Some(_) => return Err(PlacementError::CardNotInHand),
                     None => return Err(PlacementError::NoUser),
@@ -476,11 +1662,15 @@ impl Game {
                 if p.num() != self.players.num() {
                     return Ok(false);
                 }
+                self.log.push(GameEvent::Reveal {
+                    round: self.round.0,
+                    cards: p.plays().collect(),
+                });
 
                 let player_must_select_pile = p
                     .plays()
                     .find(|(_, card)| !self.table.piles.can_place(*card))
-                    .map(|(player_id, _)| player_id.to_owned());
+                    .map(|(player_id, _)| player_id);
                 let cp = std::mem::replace(p, CardPlay::new());
                 if let Some(player_id) = player_must_select_pile {
                     NextStep::PileSelection(player_id, cp)
@@ -494,47 +1684,81 @@ impl Game {
             NextStep::PileSelection(p, cp) => self.turn = Turn::PileSelection(p, cp),
             NextStep::ApplyPlay(cp) => {
                 self.apply_card_play(&cp);
-                self.turn = Turn::CardPlay(CardPlay::new());
-                return Ok(self.round.inc());
+                return Ok(self.next_round());
             }
         }
         Ok(false)
     }
 
+    /// Take back the card a player played this round so they can play another
+    fn retract_card(&mut self, player_id: PlayerId) -> Result<(), PlacementError> {
+        // Once everyone has played the cards are revealed and the turn moves on
+        let Turn::CardPlay(p) = &mut self.turn else {
+            return Err(PlacementError::PlacementOutOfTurn);
+        };
+        let card = p
+            .remove_card(player_id)
+            .ok_or(PlacementError::NothingPlaced)?;
+        self.log.push(GameEvent::CardRetracted { player_id, card });
+        Ok(())
+    }
+
     /// Selects a pile for a player (turning that pile into points)
     ///
     /// Returns `Ok(true)` if the game is over
     fn select_pile(
         &mut self,
-        user_id: &str,
+        player_id: PlayerId,
         pile_index: PileIndex,
     ) -> Result<bool, PlacementError> {
         let (cp, card, points) = match &mut self.turn {
-            Turn::PileSelection(i, cp) if i == user_id => {
-                let card = cp.remove_card(i).unwrap();
-                let points = self.table.piles.replace_pile(pile_index, card);
+            Turn::PileSelection(i, _)
+                if *i == player_id && !self.table.piles.contains(pile_index) =>
+            {
+                return Err(PlacementError::NoPile(pile_index))
+            }
+            Turn::PileSelection(i, cp) if *i == player_id => {
+                let card = cp.remove_card(player_id).unwrap();
+                let taken = self.table.piles.replace_pile(pile_index, card);
+                let points = taken.points();
+                self.log.push(GameEvent::PileSelected {
+                    player_id,
+                    card,
+                    pile: pile_index.into(),
+                    taken: taken.cards,
+                    points,
+                });
                 (cp.clone(), card, points)
             }
-            Turn::PileSelection(_, _) => todo!("Handle error"),
-            Turn::CardPlay(_) => todo!("Handle error"),
+            Turn::PileSelection(_, _) => return Err(PlacementError::NotPlayersSelection),
+            Turn::CardPlay(_) => return Err(PlacementError::PlacementOutOfTurn),
         };
-        self.apply_play_to_user(user_id, card, Some(points));
+        self.apply_play_to_user(player_id, card, Some(points));
         self.apply_card_play(&cp);
-        self.turn = Turn::CardPlay(CardPlay::new());
-        Ok(self.round.inc())
+        Ok(self.next_round())
     }
 
     fn apply_card_play(&mut self, cp: &CardPlay) {
-        for (user_id, card) in cp.plays() {
-            let points = self.table.piles.place(card).unwrap();
-            self.apply_play_to_user(user_id, card, points);
+        for (player_id, card) in cp.plays() {
+            let capacity = self.table.rules.pile_capacity;
+            let (pile_index, taken) = self.table.piles.place(card, capacity).unwrap();
+            let points = taken.as_ref().map(Pile::points);
+            self.log.push(GameEvent::Placed {
+                player_id,
+                card,
+                pile: pile_index.into(),
+                taken: taken.map(|p| p.cards).unwrap_or_default(),
+                points: points.unwrap_or_default(),
+            });
+            self.apply_play_to_user(player_id, card, points);
         }
     }
 
-    fn apply_play_to_user(&mut self, user_id: &str, card: u8, points: Option<u16>) {
-        let player = self.players.get_mut(user_id).unwrap();
+    fn apply_play_to_user(&mut self, player_id: PlayerId, card: u8, points: Option<u16>) {
+        let player = self.players.get_mut(player_id).unwrap();
         player.hand.retain(|c| *c != card);
         player.points += points.unwrap_or_default();
+        self.revealed.push(card);
     }
 
     fn player_mapping(&self) -> &PlayerMapping {
@@ -553,47 +1777,90 @@ impl Game {
         &self.table.piles
     }
 
-    fn hand_for(&self, user_id: &str) -> Option<&[u8]> {
-        self.players.get(user_id).map(|p| p.hand.as_slice())
+    fn hand_for(&self, player_id: PlayerId) -> Option<&[u8]> {
+        self.players.get(player_id).map(|p| p.hand.as_slice())
     }
 
-    fn get_player(&self, user_id: &str) -> Option<&Player> {
-        self.players.get(user_id)
+    fn get_player(&self, player_id: PlayerId) -> Option<&Player> {
+        self.players.get(player_id)
     }
 
     fn serialize_players(
         &self,
-        current_user_id: &str,
-        online_users: &HashSet<String>,
-    ) -> HashMap<String, serde_json::Value> {
+        viewer: Option<PlayerId>,
+        presence: &HashMap<PlayerId, Presence>,
+    ) -> Vec<serde_json::Value> {
         self.players
             .players_iter()
             .map(|(id, player)| {
-                let player_json = serde_json::json!({
+                let presence = player.presence(id, presence);
+                serde_json::json!({
+                    "id": id,
+                    "name": player.name,
                     "points": player.points,
-                    "me": id == current_user_id,
+                    "me": Some(id) == viewer,
                     "played": match self.played_state(id) {
                         PlayedState::Played => "played",
                         PlayedState::MustPlay => "must_play",
                         PlayedState::MustPickPile => "must_pick_pile",
                     },
-                    "online": online_users.contains(id),
-                });
-                (player.name.clone(), player_json)
+                    "online": matches!(presence, Presence::Online | Presence::Away),
+                    "afk": presence == Presence::Away,
+                    "presence": presence,
+                    "bot": player.bot,
+                    "stand_in": player.stand_in,
+                })
             })
             .collect()
     }
 
+    /// What the player is allowed to see of the game
+    fn view_for(&self, player_id: PlayerId) -> Option<PlayerView> {
+        let hand = self.hand_for(player_id)?.to_vec();
+        Some(PlayerView {
+            hand,
+            piles: self
+                .piles()
+                .serialize()
+                .into_iter()
+                .map(<[u8]>::to_vec)
+                .collect(),
+            round: self.round().0,
+            played: self.played_card_for(player_id),
+            must_select_pile: matches!(self.played_state(player_id), PlayedState::MustPickPile),
+            revealed: self.revealed.clone(),
+            round_plays: match &self.turn {
+                Turn::PileSelection(_, cp) => cp
+                    .plays()
+                    .filter(|(id, _)| *id != player_id)
+                    .map(|(_, card)| card)
+                    .collect(),
+                Turn::CardPlay(_) => Vec::new(),
+            },
+            num_players: self.players.num(),
+            rules: self.table.rules,
+        })
+    }
+
+    /// The players who have to act before the turn can continue
+    fn pending_players(&self) -> Vec<PlayerId> {
+        self.players
+            .players_iter()
+            .map(|(id, _)| id)
+            .filter(|id| !matches!(self.played_state(*id), PlayedState::Played))
+            .collect()
+    }
+
     /// Get the card played in this round by this user if they've played
-    fn played_card_for(&self, user_id: &str) -> Option<u8> {
-        self.turn.played_card_for(user_id)
+    fn played_card_for(&self, player_id: PlayerId) -> Option<u8> {
+        self.turn.played_card_for(player_id)
     }
 
-    fn played_state(&self, user_id: &str) -> PlayedState {
+    fn played_state(&self, player_id: PlayerId) -> PlayedState {
         match &self.turn {
-            Turn::CardPlay(p) if p.played_card_for(user_id).is_some() => PlayedState::Played,
+            Turn::CardPlay(p) if p.played_card_for(player_id).is_some() => PlayedState::Played,
             Turn::CardPlay(_) => PlayedState::MustPlay,
-            Turn::PileSelection(i, _) if i == user_id => PlayedState::MustPickPile,
+            Turn::PileSelection(i, _) if *i == player_id => PlayedState::MustPickPile,
             Turn::PileSelection(_, _) => PlayedState::Played,
         }
     }
@@ -611,22 +1878,28 @@ enum PlacementError {
     PlacementOutOfTurn,
     /// Played placed a card after already placing one
     RepeatedPlacement,
+    /// Tried to take back a card without having placed one
+    NothingPlaced,
     CardNotInHand,
     NoUser,
+    /// Tried to select a pile when another player must select it
+    NotPlayersSelection,
+    /// Tried to select a pile that is not on the table
+    NoPile(PileIndex),
 }
 
-#[derive(Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 enum Turn {
     /// Cards are being played
     CardPlay(CardPlay),
     /// A user must select a pile
-    PileSelection(String, CardPlay),
+    PileSelection(PlayerId, CardPlay),
 }
 impl Turn {
-    fn played_card_for(&self, user_id: &str) -> Option<u8> {
+    fn played_card_for(&self, player_id: PlayerId) -> Option<u8> {
         match self {
-            Turn::CardPlay(c) => c.played_card_for(user_id),
-            Turn::PileSelection(_, c) => c.played_card_for(user_id),
+            Turn::CardPlay(c) => c.played_card_for(player_id),
+            Turn::PileSelection(_, c) => c.played_card_for(player_id),
         }
     }
 }
@@ -634,21 +1907,21 @@ impl Turn {
 /// The cards played in a round
 ///
 /// Ordered from smallest card to largest
-#[derive(Debug, Clone)]
-struct CardPlay(Vec<(String, u8)>);
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+struct CardPlay(Vec<(PlayerId, u8)>);
 
 impl CardPlay {
     fn new() -> Self {
         Self(Vec::default())
     }
 
-    fn play_card(&mut self, user_id: String, card: u8) -> Result<(), PlacementError> {
-        if self.0.iter().any(|(uid, _)| &user_id == uid) {
+    fn play_card(&mut self, player_id: PlayerId, card: u8) -> Result<(), PlacementError> {
+        if self.0.iter().any(|(uid, _)| player_id == *uid) {
             return Err(PlacementError::RepeatedPlacement);
         }
 
-        self.0.push((user_id, card));
-        self.0.sort_by(|(_, c1), (_, c2)| c1.cmp(c2));
+        self.0.push((player_id, card));
+        self.0.sort_by_key(|(_, c)| *c);
         Ok(())
     }
 
@@ -658,51 +1931,64 @@ impl CardPlay {
     }
 
     /// All the cards played in this round
-    fn plays(&self) -> impl Iterator<Item = (&str, u8)> {
-        self.0
-            .iter()
-            .map(|(player_id, card)| (player_id.as_str(), *card))
+    fn plays(&self) -> impl Iterator<Item = (PlayerId, u8)> + '_ {
+        self.0.iter().copied()
     }
 
     /// Remove the user's card from the card play
-    fn remove_card(&mut self, user_id: &str) -> Option<u8> {
-        let i = self.0.iter().position(|(uid, _)| uid == user_id)?;
+    fn remove_card(&mut self, player_id: PlayerId) -> Option<u8> {
+        let i = self.0.iter().position(|(uid, _)| *uid == player_id)?;
         Some(self.0.remove(i).1)
     }
 
-    fn played_card_for(&self, user_id: &str) -> Option<u8> {
+    fn played_card_for(&self, player_id: PlayerId) -> Option<u8> {
         self.0
             .iter()
-            .find_map(|(uid, card)| (uid == user_id).then(|| *card))
+            .find_map(|(uid, card)| (*uid == player_id).then_some(*card))
     }
 }
 
-#[derive(serde::Serialize, Debug, PartialEq, Eq, Copy, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
 struct Round(u8);
 
 impl Round {
     // Increments round, returns true if game is over
-    fn inc(&mut self) -> bool {
+    fn inc(&mut self, rounds: u8) -> bool {
         *self = Self(self.0 + 1);
-        self.0 > 10
+        self.0 > rounds
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bot::{self, Difficulty, Strategy};
+    use crate::history::GameEvent;
+
+    /// Make a move on behalf of a player using the given strategy
+    fn auto_play(
+        state: &mut State,
+        player_id: PlayerId,
+        strategy: &mut dyn Strategy,
+    ) -> Result<(), StateError> {
+        if !state.pending_players().contains(&player_id) {
+            return Err(StateError::WrongTurn);
+        }
+        let view = state.view_for(player_id).unwrap();
+        state.apply_move(player_id, bot::decide(strategy, &view))
+    }
 
     #[test]
     fn test_lobby() {
         let mut lobby = Lobby::new();
-        lobby.join("Bill".to_owned());
-        let ted_id = lobby.join("Ted".to_owned());
+        lobby.join("Bill".to_owned()).unwrap();
+        let (_, ted_id) = lobby.join("Ted".to_owned()).unwrap();
 
         let mut players = lobby.players();
         players.sort();
         assert_eq!(players, vec!["Bill", "Ted"]);
 
-        let ted = lobby.get_player(&ted_id).unwrap();
+        let ted = lobby.get_player(ted_id).unwrap();
         assert_eq!(ted.name, "Ted");
         assert_eq!(ted.hand.len(), 10);
 
@@ -711,31 +1997,674 @@ mod tests {
 
     #[test]
     fn test_game() {
-        let table = Table::new();
-        let mut deck = Deck::new();
+        let mut table = Table::new(RuleSet::default());
         let mut players = PlayerMapping::new();
-        let bill_id = players.join("Bill".to_owned(), &mut deck);
-        let ted_id = players.join("Ted".to_owned(), &mut deck);
+        let (_, bill_id) = players.join("Bill".to_owned(), &mut table).unwrap();
+        let (_, ted_id) = players.join("Ted".to_owned(), &mut table).unwrap();
         let mut game = Game::new(table, players);
-        let bill = game.player_mapping().get(&bill_id).unwrap();
-        let ted = game.player_mapping().get(&ted_id).unwrap();
+        let bill = game.player_mapping().get(bill_id).unwrap();
+        let ted = game.player_mapping().get(ted_id).unwrap();
 
         let ted_last = *ted.hand.last().unwrap();
         let bill_first = *bill.hand.first().unwrap();
         let bill_last = *bill.hand.last().unwrap();
 
         // Can't play another player's card
-        assert!(game.play_card(&bill_id, ted_last).is_err());
+        assert!(game.play_card(bill_id, ted_last).is_err());
         // Can play player's own card
-        assert!(game.play_card(&bill_id, bill_last).is_ok());
+        assert!(game.play_card(bill_id, bill_last).is_ok());
         // Can't play twice
-        assert!(game.play_card(&bill_id, bill_first).is_err());
+        assert!(game.play_card(bill_id, bill_first).is_err());
 
-        assert!(game.play_card(&ted_id, ted_last).is_ok());
+        assert!(game.play_card(ted_id, ted_last).is_ok());
 
         assert_eq!(game.round(), Round(2));
 
         let cards_in_piles: usize = game.table.piles.0.iter().map(|p| p.num()).sum();
         assert_eq!(cards_in_piles, 6);
     }
+
+    #[test]
+    fn test_state_errors() {
+        let mut state = State::new();
+        let (_, bill_id) = state.join("Bill".to_owned()).unwrap();
+        assert_eq!(state.play_card(bill_id, 1), Err(StateError::GameNotStarted));
+        assert_eq!(
+            state.start_game(),
+            Err(StateError::NotEnoughPlayers { players: 1, min: 2 })
+        );
+
+        let (_, ted_id) = state.join("Ted".to_owned()).unwrap();
+        assert!(state.start_game().is_ok());
+        assert_eq!(state.start_game(), Err(StateError::GameAlreadyStarted));
+        assert_eq!(
+            state.join("Rufus".to_owned()),
+            Err(StateError::GameAlreadyStarted)
+        );
+        assert_eq!(
+            state.select_pile(bill_id, PileIndex(0)),
+            Err(StateError::WrongTurn)
+        );
+
+        let ted_card = state.get_player(ted_id).unwrap().hand[0];
+        assert_eq!(
+            state.play_card(bill_id, ted_card),
+            Err(StateError::CardNotInHand)
+        );
+        assert_eq!(
+            state.play_card(PlayerId(42), ted_card),
+            Err(StateError::UnknownPlayer)
+        );
+
+        let State::Game(g) = &mut state else {
+            panic!("game should be in progress")
+        };
+        g.turn = Turn::PileSelection(bill_id, CardPlay::new());
+        assert_eq!(
+            state.select_pile(bill_id, PileIndex(4)),
+            Err(StateError::InvalidPileIndex(4))
+        );
+    }
+
+    #[test]
+    fn test_round_plays_are_shown_once_revealed() {
+        let mut state = State::new();
+        let (_, bill_id) = state.join("Bill".to_owned()).unwrap();
+        let (_, ted_id) = state.join("Ted".to_owned()).unwrap();
+        state.start_game().unwrap();
+        let ted_card = state.get_player(ted_id).unwrap().hand[0];
+        state.play_card(ted_id, ted_card).unwrap();
+        assert!(state.view_for(bill_id).unwrap().round_plays.is_empty());
+
+        let State::Game(g) = &mut state else {
+            panic!("game should be in progress")
+        };
+        let mut cp = CardPlay::new();
+        cp.play_card(bill_id, 1).unwrap();
+        cp.play_card(ted_id, ted_card).unwrap();
+        g.turn = Turn::PileSelection(bill_id, cp);
+        assert_eq!(state.view_for(bill_id).unwrap().round_plays, vec![ted_card]);
+        assert_eq!(state.view_for(ted_id).unwrap().round_plays, vec![1]);
+    }
+
+    #[test]
+    fn test_auto_play() {
+        let mut state = State::new();
+        let (_, bill_id) = state.join("Bill".to_owned()).unwrap();
+        let (_, ted_id) = state.join("Ted".to_owned()).unwrap();
+        assert!(state.pending_players().is_empty());
+        state.start_game().unwrap();
+        assert_eq!(state.pending_players(), vec![bill_id, ted_id]);
+
+        let hand = state.get_player(bill_id).unwrap().hand.clone();
+        let mut strategy = StrategyKind::LowestRisk.strategy();
+        auto_play(&mut state, bill_id, strategy.as_mut()).unwrap();
+        let State::Game(g) = &state else {
+            panic!("game should be in progress")
+        };
+        assert!(hand.contains(&g.played_card_for(bill_id).unwrap()));
+        assert_eq!(state.pending_players(), vec![ted_id]);
+        assert_eq!(
+            auto_play(&mut state, bill_id, strategy.as_mut()),
+            Err(StateError::WrongTurn)
+        );
+
+        // Auto playing always makes progress until the game is over
+        while let Some(player_id) = state.pending_players().first().copied() {
+            auto_play(&mut state, player_id, strategy.as_mut()).unwrap();
+        }
+        assert!(matches!(state, State::GameOver(..)));
+    }
+
+    #[test]
+    fn test_bots() {
+        let mut state = State::new();
+        let (_, bill_id) = state.join("Bill".to_owned()).unwrap();
+        let bot_id = state.add_bot(StrategyKind::Random).unwrap();
+        let other_bot_id = state.add_bot(StrategyKind::Random).unwrap();
+        assert_eq!(state.get_player(bot_id).unwrap().name, "Random Bot 1");
+        assert_eq!(state.get_player(other_bot_id).unwrap().name, "Random Bot 2");
+        assert_eq!(state.controller(bot_id), Some(StrategyKind::Random));
+        assert_eq!(state.controller(bill_id), None);
+
+        assert_eq!(state.remove_bot(bill_id), Err(StateError::NotABot));
+        state.remove_bot(other_bot_id).unwrap();
+        assert!(state.get_player(other_bot_id).is_none());
+
+        state.start_game().unwrap();
+        assert_eq!(
+            state.set_stand_in(bot_id, Some(StrategyKind::LowestRisk)),
+            Err(StateError::NotHuman)
+        );
+        state
+            .set_stand_in(bill_id, Some(StrategyKind::MinBullheads))
+            .unwrap();
+        assert_eq!(state.controller(bill_id), Some(StrategyKind::MinBullheads));
+    }
+
+    #[test]
+    fn test_sessions() {
+        let mut lobby = Lobby::new();
+        let (bill_token, bill_id) = lobby.join("Bill".to_owned()).unwrap();
+        let (ted_token, ted_id) = lobby.join(" Ted ".to_owned()).unwrap();
+
+        assert_ne!(bill_id, ted_id);
+        assert_ne!(bill_token, ted_token);
+        assert_eq!(bill_token.len(), 64);
+        assert_eq!(lobby.players.player_for_token(&bill_token), Some(bill_id));
+        assert_eq!(lobby.players.player_for_token(&ted_token), Some(ted_id));
+        assert_eq!(lobby.players.player_for_token(&bill_id.to_string()), None);
+
+        assert_eq!(lobby.join("ted".to_owned()), Err(StateError::NameTaken));
+        assert_eq!(lobby.join("  ".to_owned()), Err(StateError::InvalidName));
+    }
+
+    #[test]
+    fn test_replay_matches_game() {
+        let mut state = State::new();
+        state.join("Bill".to_owned()).unwrap();
+        state.add_bot(StrategyKind::MinBullheads).unwrap();
+        state.add_bot(StrategyKind::Random).unwrap();
+        state.start_game().unwrap();
+        while let Some(player_id) = state.pending_players().first().copied() {
+            let view = state.view_for(player_id).unwrap();
+            let mv = bot::decide(StrategyKind::LowestRisk.strategy().as_mut(), &view);
+            state.apply_move(player_id, mv).unwrap();
+        }
+
+        let log = state.finished_log().unwrap().clone();
+        let played = log
+            .events
+            .iter()
+            .filter(|e| matches!(e, GameEvent::CardPlayed { .. }))
+            .count();
+        assert_eq!(played, 30);
+        let reveals = log
+            .events
+            .iter()
+            .filter(|e| matches!(e, GameEvent::Reveal { .. }))
+            .count();
+        assert_eq!(reveals, 10);
+
+        let first = log.replay(0).unwrap();
+        assert!(matches!(first.event, GameEvent::Deal { .. }));
+        assert!(first.hands.values().all(|h| h.len() == 10));
+
+        let last = log.replay(log.events.len() - 1).unwrap();
+        assert!(last.hands.values().all(Vec::is_empty));
+        assert!(last.played.is_empty());
+        assert_eq!(last.round, 10);
+        for (player_id, points) in &last.points {
+            assert_eq!(*points, state.get_player(*player_id).unwrap().points);
+        }
+        let on_piles: usize = last.piles.iter().map(Vec::len).sum();
+        let taken: usize = log
+            .events
+            .iter()
+            .map(|e| match e {
+                GameEvent::Placed { taken, .. } | GameEvent::PileSelected { taken, .. } => {
+                    taken.len()
+                }
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(on_piles + taken, 4 + 30);
+        assert!(log.replay(log.events.len()).is_none());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut state = State::new();
+        let (bill_token, bill_id) = state.join("Bill".to_owned()).unwrap();
+        let bot_id = state
+            .add_bot(StrategyKind::Ismcts(Difficulty::Easy))
+            .unwrap();
+        state.start_game().unwrap();
+        let mut strategy = StrategyKind::LowestRisk.strategy();
+        auto_play(&mut state, bill_id, strategy.as_mut()).unwrap();
+
+        let saved = serde_json::to_string(&state).unwrap();
+        let mut loaded: State = serde_json::from_str(&saved).unwrap();
+        assert_eq!(loaded.player_for_token(&bill_token), Some(bill_id));
+        assert_eq!(loaded.view_for(bill_id), state.view_for(bill_id));
+        assert_eq!(loaded.view_for(bot_id), state.view_for(bot_id));
+        assert_eq!(
+            loaded.controller(bot_id),
+            Some(StrategyKind::Ismcts(Difficulty::Easy))
+        );
+        assert_eq!(loaded.human_players(), vec![bill_id]);
+
+        // The loaded game carries on to the end
+        while let Some(player_id) = loaded.pending_players().first().copied() {
+            auto_play(&mut loaded, player_id, strategy.as_mut()).unwrap();
+        }
+        let log = loaded.finished_log().unwrap();
+        assert!(matches!(log.events[0], GameEvent::Deal { .. }));
+        let saved = serde_json::to_string(&loaded).unwrap();
+        let loaded: State = serde_json::from_str(&saved).unwrap();
+        assert_eq!(loaded.finished_log().unwrap().events, log.events);
+    }
+
+    #[test]
+    fn test_retract_card() {
+        let mut state = State::new();
+        let (_, bill_id) = state.join("Bill".to_owned()).unwrap();
+        let (_, ted_id) = state.join("Ted".to_owned()).unwrap();
+        state.start_game().unwrap();
+        assert_eq!(state.retract_card(bill_id), Err(StateError::NotPlayed));
+
+        let hand = state.get_player(bill_id).unwrap().hand.clone();
+        state.play_card(bill_id, hand[0]).unwrap();
+        state.retract_card(bill_id).unwrap();
+        assert_eq!(state.view_for(bill_id).unwrap().played, None);
+        assert!(state.pending_players().contains(&bill_id));
+        let replay = state.log().unwrap().replay(2).unwrap();
+        assert!(replay.played.is_empty());
+
+        // The player picks another card and the round carries on as usual
+        state.play_card(bill_id, hand[1]).unwrap();
+        let card = state.get_player(ted_id).unwrap().hand[0];
+        state.play_card(ted_id, card).unwrap();
+        let log = state.log().unwrap();
+        let revealed = log.events.iter().find_map(|e| match e {
+            GameEvent::Reveal { cards, .. } => Some(cards.clone()),
+            _ => None,
+        });
+        assert!(revealed.unwrap().contains(&(bill_id, hand[1])));
+        // Once the cards are revealed there is nothing left to take back
+        assert!(state.retract_card(bill_id).is_err());
+    }
+
+    #[test]
+    fn test_pause() {
+        let mut state = State::new();
+        let (_, bill_id) = state.join("Bill".to_owned()).unwrap();
+        state.join("Ted".to_owned()).unwrap();
+        assert_eq!(state.set_paused(true), Err(StateError::GameNotStarted));
+        state.start_game().unwrap();
+        assert_eq!(state.set_paused(false), Err(StateError::NotPaused));
+        state.set_paused(true).unwrap();
+        assert_eq!(state.set_paused(true), Err(StateError::GamePaused));
+
+        let card = state.get_player(bill_id).unwrap().hand[0];
+        assert_eq!(state.play_card(bill_id, card), Err(StateError::GamePaused));
+        assert!(state.pending_players().is_empty());
+        let presence = HashMap::new();
+        assert_eq!(state.serialize_for_user(bill_id, &presence)["paused"], true);
+
+        // Pausing survives a restart of the server
+        let saved = serde_json::to_string(&state).unwrap();
+        let mut loaded: State = serde_json::from_str(&saved).unwrap();
+        assert!(loaded.is_paused());
+        loaded.set_paused(false).unwrap();
+        loaded.play_card(bill_id, card).unwrap();
+    }
+
+    #[test]
+    fn test_rules() {
+        let mut state = State::new();
+        let (_, bill_id) = state.join("Bill".to_owned()).unwrap();
+        state.add_bot(StrategyKind::LowestRisk).unwrap();
+
+        let short = RuleSet {
+            hand_size: 6,
+            rounds: 6,
+            piles: 5,
+            pile_capacity: 4,
+            match_points: None,
+        };
+        assert!(matches!(
+            state.set_rules(RuleSet { rounds: 7, ..short }),
+            Err(StateError::InvalidRules(_))
+        ));
+        assert!(matches!(
+            state.set_rules(RuleSet { piles: 0, ..short }),
+            Err(StateError::InvalidRules(_))
+        ));
+        assert!(matches!(
+            state.set_rules(RuleSet {
+                hand_size: 51,
+                rounds: 6,
+                ..short
+            }),
+            Err(StateError::InvalidRules(_))
+        ));
+        state.set_rules(short).unwrap();
+        assert_eq!(state.get_player(bill_id).unwrap().hand.len(), 6);
+
+        state.start_game().unwrap();
+        let view = state.view_for(bill_id).unwrap();
+        assert_eq!(view.piles.len(), 5);
+        assert_eq!(view.rules, short);
+        assert_eq!(state.set_rules(short), Err(StateError::GameAlreadyStarted));
+
+        let mut strategy = StrategyKind::LowestRisk.strategy();
+        while let Some(player_id) = state.pending_players().first().copied() {
+            let view = state.view_for(player_id).unwrap();
+            assert!(view.piles.iter().all(|p| p.len() <= 4));
+            auto_play(&mut state, player_id, strategy.as_mut()).unwrap();
+        }
+        let log = state.finished_log().unwrap();
+        let reveals = log
+            .events
+            .iter()
+            .filter(|e| matches!(e, GameEvent::Reveal { .. }))
+            .count();
+        assert_eq!(reveals, 6);
+
+        // The rules carry over to the next game
+        state.restart();
+        assert_eq!(state.get_player(bill_id).unwrap().hand.len(), 6);
+
+        let mut state = State::new();
+        state
+            .set_rules(RuleSet {
+                hand_size: 50,
+                rounds: 10,
+                ..RuleSet::default()
+            })
+            .unwrap();
+        state.join("Bill".to_owned()).unwrap();
+        state.join("Ted".to_owned()).unwrap();
+        assert_eq!(state.join("Rufus".to_owned()), Err(StateError::TableFull));
+    }
+
+    #[test]
+    fn test_match() {
+        let mut state = State::new();
+        let (_, bill_id) = state.join("Bill".to_owned()).unwrap();
+        state.add_bot(StrategyKind::Random).unwrap();
+        state.add_bot(StrategyKind::Random).unwrap();
+        assert!(matches!(
+            state.set_rules(RuleSet {
+                match_points: Some(0),
+                ..RuleSet::default()
+            }),
+            Err(StateError::InvalidRules(_))
+        ));
+        state
+            .set_rules(RuleSet {
+                match_points: Some(30),
+                ..RuleSet::default()
+            })
+            .unwrap();
+        state.start_game().unwrap();
+
+        let mut strategy = StrategyKind::Random.strategy();
+        let mut hands = 1;
+        while let Some(player_id) = state.pending_players().first().copied() {
+            auto_play(&mut state, player_id, strategy.as_mut()).unwrap();
+            if let State::Game(g) = &state {
+                hands = g.hand;
+                // Every new hand starts with a full hand for everyone
+                if g.round == Round(1) && g.pending_players().len() == 3 {
+                    assert_eq!(g.hand_for(bill_id).unwrap().len(), 10);
+                }
+            }
+        }
+
+        let State::GameOver(players, log) = &state else {
+            panic!("game should be over")
+        };
+        assert!(players.players_iter().any(|(_, p)| p.points >= 30));
+        for (id, p) in players.players_iter() {
+            let per_hand = log.hand_points(id);
+            assert_eq!(per_hand.len(), hands);
+            assert_eq!(per_hand.iter().sum::<u16>(), p.points);
+        }
+        let last = log.replay(log.events.len() - 1).unwrap();
+        assert_eq!(last.hand, hands);
+        for (id, p) in players.players_iter() {
+            assert_eq!(last.points[&id], p.points);
+        }
+    }
+
+    #[test]
+    fn test_spectator_view() {
+        let mut state = State::new();
+        let (_, bill_id) = state.join("Bill".to_owned()).unwrap();
+        state.join("Ted".to_owned()).unwrap();
+        let presence = HashMap::new();
+        let watched = state.serialize_for_spectator(&presence);
+        assert_eq!(watched["spectator"], true);
+        assert!(watched["players"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|p| p["me"] == false));
+
+        state.start_game().unwrap();
+        let card = state.get_player(bill_id).unwrap().hand[0];
+        state.play_card(bill_id, card).unwrap();
+
+        let seen_by_bill = state.serialize_for_user(bill_id, &presence);
+        assert_eq!(seen_by_bill["round"]["played"], card);
+        assert_eq!(seen_by_bill["hand"].as_array().unwrap().len(), 10);
+
+        let watched = state.serialize_for_spectator(&presence);
+        assert!(watched["round"]["played"].is_null());
+        assert!(watched.get("hand").is_none());
+        assert_eq!(watched["piles"], seen_by_bill["piles"]);
+        assert_eq!(watched["players"][0]["played"], "played");
+        assert_eq!(watched["players"][1]["played"], "must_play");
+    }
+
+    #[test]
+    fn test_afk_players_are_still_online() {
+        let mut state = State::new();
+        let (_, bill_id) = state.join("Bill".to_owned()).unwrap();
+        let (_, ted_id) = state.join("Ted".to_owned()).unwrap();
+        state.start_game().unwrap();
+        let presence = HashMap::from([(bill_id, Presence::Away), (ted_id, Presence::Online)]);
+        let seen_by_ted = state.serialize_for_user(ted_id, &presence);
+        assert_eq!(seen_by_ted["players"][0]["online"], true);
+        assert_eq!(seen_by_ted["players"][0]["afk"], true);
+        assert_eq!(seen_by_ted["players"][0]["presence"], "away");
+        assert_eq!(seen_by_ted["players"][1]["afk"], false);
+    }
+
+    #[test]
+    fn test_delayed_spectator_view() {
+        let mut state = State::new();
+        let (_, bill_id) = state.join("Bill".to_owned()).unwrap();
+        let (_, ted_id) = state.join("Ted".to_owned()).unwrap();
+        state.start_game().unwrap();
+        let presence = HashMap::new();
+        let start = state.serialize_for_spectator(&presence);
+
+        let bill_card = state.get_player(bill_id).unwrap().hand[0];
+        let ted_card = state.get_player(ted_id).unwrap().hand[0];
+        state.play_card(bill_id, bill_card).unwrap();
+        let bill_played = state.log().unwrap().events.len();
+        state.play_card(ted_id, ted_card).unwrap();
+
+        // Only the deal is visible
+        let watched = state.serialize_for_delayed_spectator(1, &presence);
+        assert_eq!(watched["delayed"], true);
+        assert_eq!(watched["waiting"], start["waiting"]);
+        assert_eq!(watched["piles"], start["piles"]);
+        assert_eq!(watched["players"][0]["played"], "must_play");
+        assert!(watched.get("hand").is_none());
+
+        let watched = state.serialize_for_delayed_spectator(bill_played, &presence);
+        assert_eq!(watched["players"][0]["played"], "played");
+        assert_eq!(watched["players"][1]["played"], "must_play");
+        assert_eq!(watched["round"]["played"], Value::Null);
+
+        let events = state.log().unwrap().events.len();
+        let watched = state.serialize_for_delayed_spectator(events, &presence);
+        assert!(watched.get("delayed").is_none());
+        assert_eq!(watched, state.serialize_for_spectator(&presence));
+    }
+
+    #[test]
+    fn test_host() {
+        let mut state = State::new();
+        let bot_id = state.add_bot(StrategyKind::Random).unwrap();
+        assert_eq!(state.host(), None);
+        let (_, bill_id) = state.join("Bill".to_owned()).unwrap();
+        let (_, ted_id) = state.join("Ted".to_owned()).unwrap();
+        let (_, al_id) = state.join("Al".to_owned()).unwrap();
+        assert_eq!(state.host(), Some(bill_id));
+        assert_eq!(
+            state.serialize_for_user(ted_id, &HashMap::new())["host"],
+            json!(bill_id)
+        );
+
+        assert_eq!(state.set_host(bot_id), Err(StateError::NotHuman));
+        state.set_host(ted_id).unwrap();
+        assert_eq!(state.host(), Some(ted_id));
+
+        assert_eq!(state.kick(ted_id, ted_id), Err(StateError::CannotKickSelf));
+        state.kick(ted_id, bill_id).unwrap();
+        assert!(state.get_player(bill_id).is_none());
+
+        // Kicking the host hands the role to the human who joined first
+        state.kick(al_id, ted_id).unwrap();
+        assert_eq!(state.host(), Some(al_id));
+
+        state.start_game().unwrap();
+        assert_eq!(state.host(), Some(al_id));
+        assert_eq!(
+            state.kick(al_id, bot_id),
+            Err(StateError::GameAlreadyStarted)
+        );
+    }
+
+    #[test]
+    fn test_ready() {
+        let mut state = State::new();
+        let (_, bill_id) = state.join("Bill".to_owned()).unwrap();
+        let (_, ted_id) = state.join("Ted".to_owned()).unwrap();
+        let bot_id = state.add_bot(StrategyKind::Random).unwrap();
+        let present = HashSet::from([bill_id, ted_id]);
+        assert!(!state.everyone_ready(&present));
+
+        state.set_ready(bill_id, true).unwrap();
+        assert!(!state.everyone_ready(&present));
+        // Ted being away doesn't hold everyone else up
+        assert!(state.everyone_ready(&HashSet::from([bill_id])));
+        // Somebody has to be there though
+        assert!(!state.everyone_ready(&HashSet::new()));
+
+        state.set_ready(ted_id, true).unwrap();
+        assert!(state.everyone_ready(&present));
+        let lobby = state.serialize_for_user(bill_id, &HashMap::new());
+        assert!(lobby["players"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|p| p["ready"] == true));
+        assert_eq!(state.set_ready(bot_id, false), Err(StateError::NotHuman));
+
+        state.set_ready(ted_id, false).unwrap();
+        assert!(!state.everyone_ready(&present));
+
+        state.start_game().unwrap();
+        assert!(!state.everyone_ready(&present));
+        assert_eq!(
+            state.set_ready(bill_id, true),
+            Err(StateError::GameAlreadyStarted)
+        );
+        state.restart();
+        assert!(!state.get_player(bill_id).unwrap().ready);
+    }
+
+    #[test]
+    fn test_rematch() {
+        let mut state = State::new();
+        let (_, bill_id) = state.join("Bill".to_owned()).unwrap();
+        let bot_id = state.add_bot(StrategyKind::Random).unwrap();
+        assert_eq!(state.rematch(), Err(StateError::GameNotStarted));
+        state.start_game().unwrap();
+        assert_eq!(state.rematch(), Err(StateError::GameNotOver));
+        assert_eq!(state.return_to_lobby(), Err(StateError::GameNotOver));
+        while let Some(player_id) = state.pending_players().first().copied() {
+            let view = state.view_for(player_id).unwrap();
+            let mv = bot::decide(StrategyKind::LowestRisk.strategy().as_mut(), &view);
+            state.apply_move(player_id, mv).unwrap();
+        }
+
+        state.rematch().unwrap();
+        assert!(matches!(state, State::Game(_)));
+        assert_eq!(state.human_players(), vec![bill_id]);
+        assert_eq!(state.controller(bot_id), Some(StrategyKind::Random));
+        assert_eq!(state.get_player(bill_id).unwrap().points, 0);
+        assert_eq!(state.get_player(bill_id).unwrap().hand.len(), 10);
+    }
+
+    #[test]
+    fn test_max_players() {
+        let mut state = State::new();
+        assert_eq!(RuleSet::default().max_players(), 10);
+        for i in 0..10 {
+            state.join(format!("Player {i}")).unwrap();
+        }
+        assert_eq!(state.join("Bill".to_owned()), Err(StateError::TableFull));
+        assert_eq!(
+            state.add_bot(StrategyKind::Random),
+            Err(StateError::TableFull)
+        );
+
+        let (token, bill_id) = state.wait("Bill".to_owned()).unwrap();
+        assert_eq!(state.player_for_token(&token), Some(bill_id));
+        assert!(state.get_player(bill_id).is_none());
+        assert_eq!(state.wait("bill".to_owned()), Err(StateError::NameTaken));
+        let lobby = state.serialize_for_user(bill_id, &HashMap::new());
+        assert_eq!(lobby["max_players"], 10);
+        assert_eq!(lobby["waiting"][0]["me"], true);
+
+        // Bill gets the first seat that is free
+        let first = PlayerId(0);
+        let host = state.host().unwrap();
+        state.kick(host, PlayerId(1)).unwrap();
+        assert!(state.get_player(bill_id).is_some());
+        assert_eq!(state.host(), Some(first));
+
+        // Fewer cards per hand make room for more players
+        let rules = RuleSet {
+            hand_size: 5,
+            rounds: 5,
+            ..RuleSet::default()
+        };
+        state.set_rules(rules).unwrap();
+        assert_eq!(rules.max_players(), 20);
+        state.join("Ted".to_owned()).unwrap();
+    }
+
+    #[test]
+    fn test_take_seat() {
+        let mut state = State::new();
+        let (bill_token, bill_id) = state.join("Bill".to_owned()).unwrap();
+        let (_, ted_id) = state.join("Ted".to_owned()).unwrap();
+        state.start_game().unwrap();
+        assert_eq!(
+            state.join("Al".to_owned()),
+            Err(StateError::GameAlreadyStarted)
+        );
+        let (al_token, al_id) = state.wait("Al".to_owned()).unwrap();
+        assert!(state.is_waiting(al_id));
+        assert!(!state.is_waiting(bill_id));
+
+        let card = state.get_player(bill_id).unwrap().hand[0];
+        state.play_card(bill_id, card).unwrap();
+        let hand = state.get_player(bill_id).unwrap().hand.clone();
+        assert_eq!(
+            state.take_seat(bill_id, ted_id),
+            Err(StateError::NotWaiting)
+        );
+        state.take_seat(bill_id, al_id).unwrap();
+
+        // Al plays on from where Bill left off
+        let seat = state.get_player(bill_id).unwrap();
+        assert_eq!(seat.name, "Al");
+        assert_eq!(seat.hand, hand);
+        assert!(!state.is_waiting(al_id));
+        assert_eq!(state.player_for_token(&al_token), Some(bill_id));
+        assert_eq!(state.player_for_token(&bill_token), None);
+        assert_eq!(
+            state.play_card(bill_id, hand[1]),
+            Err(StateError::AlreadyPlayed)
+        );
+    }
 }
@@ -0,0 +1,165 @@
+use std::collections::BTreeSet;
+use std::time::Instant;
+
+use crate::state::{PlayerId, StateError};
+
+/// What players can vote on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VoteKind {
+    /// Abandon the game in progress and go back to the lobby
+    Restart,
+    /// Let a player from the waiting list take over an abandoned seat
+    TakeSeat {
+        seat: PlayerId,
+        player_id: PlayerId,
+    },
+    /// Stop the game until it is resumed
+    Pause,
+    Resume,
+}
+
+/// How many of the voters have to agree for a vote to pass
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Majority {
+    /// More than half
+    #[default]
+    Simple,
+    /// At least two thirds
+    TwoThirds,
+    /// Everyone
+    Unanimous,
+}
+
+impl Majority {
+    /// How many yes votes are needed out of `voters`
+    fn required(self, voters: usize) -> usize {
+        match self {
+            Majority::Simple => voters / 2 + 1,
+            Majority::TwoThirds => (voters * 2).div_ceil(3),
+            Majority::Unanimous => voters,
+        }
+    }
+}
+
+/// A vote the players of a room are taking
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Vote {
+    pub kind: VoteKind,
+    pub started_by: PlayerId,
+    /// The players who may vote, which are the humans connected when the vote started
+    voters: BTreeSet<PlayerId>,
+    yes: BTreeSet<PlayerId>,
+    no: BTreeSet<PlayerId>,
+    /// How many yes votes the vote needs to pass
+    required: usize,
+    /// When the vote runs out in milliseconds since the Unix epoch
+    expires_at: u64,
+    /// Tells votes apart so an expiry timer only ends the vote it was started for
+    #[serde(skip)]
+    pub started: Instant,
+}
+
+impl Vote {
+    /// Start a vote, counting the player who started it as voting yes if they
+    /// are one of the voters
+    pub fn new(
+        kind: VoteKind,
+        started_by: PlayerId,
+        voters: BTreeSet<PlayerId>,
+        majority: Majority,
+        expires_at: u64,
+    ) -> Self {
+        let required = majority.required(voters.len());
+        let yes = voters.get(&started_by).copied().into_iter().collect();
+        Self {
+            kind,
+            started_by,
+            voters,
+            yes,
+            no: BTreeSet::new(),
+            required,
+            expires_at,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn cast(&mut self, player_id: PlayerId, yes: bool) -> Result<(), StateError> {
+        if !self.voters.contains(&player_id) {
+            return Err(StateError::NotAVoter);
+        }
+        if self.yes.contains(&player_id) || self.no.contains(&player_id) {
+            return Err(StateError::AlreadyVoted);
+        }
+        if yes {
+            self.yes.insert(player_id);
+        } else {
+            self.no.insert(player_id);
+        }
+        Ok(())
+    }
+
+    /// Whether the vote passed or failed, or `None` while it could still go either way
+    pub fn outcome(&self) -> Option<bool> {
+        if self.yes.len() >= self.required {
+            return Some(true);
+        }
+        let undecided = self.voters.len() - self.yes.len() - self.no.len();
+        if self.yes.len() + undecided < self.required {
+            return Some(false);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vote(voters: u32, majority: Majority) -> Vote {
+        let voters = (0..voters).map(PlayerId::from).collect();
+        Vote::new(VoteKind::Restart, PlayerId::from(0), voters, majority, 0)
+    }
+
+    #[test]
+    fn test_majorities() {
+        assert_eq!(Majority::Simple.required(4), 3);
+        assert_eq!(Majority::Simple.required(5), 3);
+        assert_eq!(Majority::TwoThirds.required(3), 2);
+        assert_eq!(Majority::TwoThirds.required(4), 3);
+        assert_eq!(Majority::Unanimous.required(4), 4);
+
+        // A player on their own decides straight away
+        assert_eq!(vote(1, Majority::Unanimous).outcome(), Some(true));
+
+        // Somebody who isn't a voter has to be let in by the voters
+        let voters = BTreeSet::from([PlayerId::from(0), PlayerId::from(1)]);
+        let kind = VoteKind::TakeSeat {
+            seat: PlayerId::from(1),
+            player_id: PlayerId::from(2),
+        };
+        let v = Vote::new(kind, PlayerId::from(2), voters, Majority::Simple, 0);
+        assert_eq!(v.outcome(), None);
+        assert!(v.yes.is_empty());
+    }
+
+    #[test]
+    fn test_cast() {
+        let mut v = vote(4, Majority::Simple);
+        assert_eq!(v.outcome(), None);
+        assert_eq!(
+            v.cast(PlayerId::from(0), false),
+            Err(StateError::AlreadyVoted)
+        );
+        assert_eq!(v.cast(PlayerId::from(7), true), Err(StateError::NotAVoter));
+        v.cast(PlayerId::from(1), false).unwrap();
+        assert_eq!(v.outcome(), None);
+        v.cast(PlayerId::from(2), false).unwrap();
+        assert_eq!(v.outcome(), Some(false));
+
+        let mut v = vote(3, Majority::TwoThirds);
+        v.cast(PlayerId::from(1), true).unwrap();
+        assert_eq!(v.outcome(), Some(true));
+    }
+}